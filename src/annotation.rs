@@ -2,15 +2,18 @@ use pyo3::exceptions::{PyException, PyIndexError, PyKeyError, PyRuntimeError, Py
 use pyo3::prelude::*;
 use pyo3::pyclass::CompareOp;
 use pyo3::types::*;
+use std::collections::HashSet;
 use std::ops::FnOnce;
 use std::sync::{Arc, RwLock};
 
-use crate::annotationdata::PyAnnotationData;
+use crate::annotationdata::{
+    py_into_databuilder, py_into_datavalue, PyAnnotationData, PyDataKey, TypedValue,
+};
 use crate::annotationdataset::PyAnnotationDataSet;
 use crate::annotationstore::MapStore;
 use crate::error::PyStamError;
-use crate::resources::{PyTextResource, PyTextSelection};
-use crate::selector::PySelector;
+use crate::resources::{PyOffset, PyTextResource, PyTextSelection};
+use crate::selector::{py_into_selector, PySelector};
 use stam::*;
 
 #[pyclass(name = "Annotation")]
@@ -51,6 +54,102 @@ impl PyAnnotation {
         })
     }
 
+    /// Tests whether this annotation shares any AnnotationData with another annotation (in the same store)
+    fn shares_data(&self, other: PyRef<Self>) -> PyResult<bool> {
+        Ok(!self.common_data_handles(&other)?.is_empty())
+    }
+
+    /// Tests whether this annotation has the same content as another annotation, which may be in another store.
+    /// Annotations are equal in content if they ultimately target the same text spans (in resources with the same ID)
+    /// and have the same data (the same keys and values of the same type, in sets with the same ID). Their own IDs are not considered.
+    fn content_equals(&self, other: PyRef<Self>) -> PyResult<bool> {
+        let content = self.map_store(|store| {
+            let annotation: &Annotation = store.get(self.handle)?;
+            Ok(annotation_content(store, annotation))
+        })?;
+        let other_content = other.map_store(|store| {
+            let annotation: &Annotation = store.get(other.handle)?;
+            Ok(annotation_content(store, annotation))
+        })?;
+        Ok(content == other_content)
+    }
+
+    /// Tests whether the text of this annotation overlaps with that of another annotation (in the same store)
+    fn overlaps(&self, other: PyRef<Self>) -> PyResult<bool> {
+        self.test_relation(&other, SpanRelation::Overlaps)
+    }
+
+    /// Tests whether the text of this annotation embeds all text of another annotation (in the same store)
+    fn embeds(&self, other: PyRef<Self>) -> PyResult<bool> {
+        self.test_relation(&other, SpanRelation::Embeds)
+    }
+
+    /// Tests whether all text of this annotation is embedded in the text of another annotation (in the same store)
+    fn embedded_in(&self, other: PyRef<Self>) -> PyResult<bool> {
+        self.test_relation(&other, SpanRelation::EmbeddedIn)
+    }
+
+    /// Tests whether the text of this annotation ends exactly where the text of another annotation (in the same store) begins
+    fn precedes(&self, other: PyRef<Self>) -> PyResult<bool> {
+        self.test_relation(&other, SpanRelation::Precedes)
+    }
+
+    /// Tests whether the text of this annotation begins exactly where the text of another annotation (in the same store) ends
+    fn succeeds(&self, other: PyRef<Self>) -> PyResult<bool> {
+        self.test_relation(&other, SpanRelation::Succeeds)
+    }
+
+    /// Tests whether the text of this annotation comes entirely before the text of another annotation (in the same store)
+    fn before(&self, other: PyRef<Self>) -> PyResult<bool> {
+        self.test_relation(&other, SpanRelation::Before)
+    }
+
+    /// Tests whether the text of this annotation comes entirely after the text of another annotation (in the same store)
+    fn after(&self, other: PyRef<Self>) -> PyResult<bool> {
+        self.test_relation(&other, SpanRelation::After)
+    }
+
+    /// Tests whether this annotation is equivalent to another annotation, which may be in another store: both must have
+    /// the same target selector (referring to resources, annotations and sets with the same IDs) and the same data.
+    /// Unlike `content_equals()`, the selectors are compared as they are rather than the text they resolve to.
+    /// Annotations that refer to anything without a public ID (in their selector or data) are never equivalent, as
+    /// such targets can not be identified across stores.
+    /// The `==` operator, on the other hand, tests whether two instances refer to the very same annotation.
+    fn equivalent(&self, other: PyRef<Self>) -> PyResult<bool> {
+        let content = self.map_store(|store| {
+            let annotation: &Annotation = store.get(self.handle)?;
+            Ok(equivalence_content(store, annotation))
+        })?;
+        let other_content = other.map_store(|store| {
+            let annotation: &Annotation = store.get(other.handle)?;
+            Ok(equivalence_content(store, annotation))
+        })?;
+        Ok(match (content, other_content) {
+            (Some(content), Some(other_content)) => content == other_content,
+            _ => false,
+        })
+    }
+
+    /// Returns the AnnotationData this annotation has in common with another annotation (in the same store), as a tuple
+    fn common_data<'py>(&self, other: PyRef<Self>, py: Python<'py>) -> PyResult<&'py PyTuple> {
+        let elements: Vec<Py<PyAnnotationData>> = self
+            .common_data_handles(&other)?
+            .into_iter()
+            .map(|(set, handle)| {
+                Py::new(
+                    py,
+                    PyAnnotationData {
+                        set,
+                        handle,
+                        store: self.store.clone(),
+                    },
+                )
+                .expect("Annotation.common_data() wrapping PyAnnotationData")
+            })
+            .collect();
+        Ok(PyTuple::new(py, elements))
+    }
+
     /// Returns a Selector (AnnotationSelector) pointing to this Annotation
     /// If the annotation references any text, so will this
     fn selector(&self) -> PyResult<PySelector> {
@@ -84,14 +183,47 @@ impl PyAnnotation {
         })
     }
 
+    /// Returns a stable hash (as a hexadecimal string) of the text of this annotation, suitable as a cache key across runs.
+    /// The text spans are taken in textual order and joined by a space, and all whitespace is normalized to single spaces,
+    /// so discontinuous annotations hash deterministically.
+    fn text_hash(&self) -> PyResult<String> {
+        self.map_store(|store| {
+            let annotation: &Annotation = store.get(self.handle)?;
+            let mut text = String::new();
+            for (reshandle, begin, end) in sorted_spans(store, annotation) {
+                let resource: &TextResource = store.get(reshandle)?;
+                for word in resource
+                    .text_slice(&Offset::simple(begin, end))?
+                    .split_whitespace()
+                {
+                    if !text.is_empty() {
+                        text.push(' ');
+                    }
+                    text.push_str(word);
+                }
+            }
+            Ok(format!("{:016x}", fnv1a(text.as_bytes())))
+        })
+    }
+
     /// Returns the textselections of the annotation.
     /// Note that this will always return a tuple (even it if only contains a single element),
     /// as an annotation may reference multiple text selections.
-    fn textselections<'py>(&self, py: Python<'py>) -> PyResult<&'py PyTuple> {
+    /// The text selections are returned in the order of the selector, unless `textual_order` is set,
+    /// in which case they are sorted by position (grouped per resource).
+    #[pyo3(signature = (textual_order=false))]
+    fn textselections<'py>(&self, textual_order: bool, py: Python<'py>) -> PyResult<&'py PyTuple> {
         self.map_store(|store| {
             let annotation: &Annotation = store.get(self.handle)?;
-            let elements: Vec<Py<PyTextSelection>> = store
-                .textselections_by_annotation(annotation)
+            let mut textselections: Vec<(TextResourceHandle, TextSelection)> =
+                store.textselections_by_annotation(annotation).collect();
+            if textual_order {
+                textselections.sort_by_key(|(reshandle, textselection)| {
+                    (*reshandle, textselection.begin(), textselection.end())
+                });
+            }
+            let elements: Vec<Py<PyTextSelection>> = textselections
+                .into_iter()
                 .map(|(reshandle, textselection)| {
                     Py::new(
                         py,
@@ -108,6 +240,73 @@ impl PyAnnotation {
         })
     }
 
+    /// Returns all the text spans this annotation ultimately refers to, as a list of (TextResource, Offset) tuples.
+    /// Complex selectors are flattened and AnnotationSelectors are followed, the offsets are always simple and absolute.
+    fn target_spans(&self) -> PyResult<Vec<(PyTextResource, PyOffset)>> {
+        self.map_store(|store| {
+            let annotation: &Annotation = store.get(self.handle)?;
+            Ok(store
+                .textselections_by_annotation(annotation)
+                .map(|(reshandle, textselection)| {
+                    (
+                        PyTextResource {
+                            handle: reshandle,
+                            store: self.store.clone(),
+                        },
+                        PyOffset {
+                            offset: Offset::simple(textselection.begin(), textselection.end()),
+                        },
+                    )
+                })
+                .collect())
+        })
+    }
+
+    /// Returns the total number of characters (unicode points) this annotation covers, summed over all its text selections
+    fn textlen(&self) -> PyResult<usize> {
+        self.map_store(|store| {
+            let annotation: &Annotation = store.get(self.handle)?;
+            Ok(store
+                .textselections_by_annotation(annotation)
+                .map(|(_, textselection)| textselection.end() - textselection.begin())
+                .sum())
+        })
+    }
+
+    /// Tests whether this annotation targets multiple non-contiguous text spans (possibly in different resources)
+    fn is_discontinuous(&self) -> PyResult<bool> {
+        self.map_store(|store| {
+            let annotation: &Annotation = store.get(self.handle)?;
+            let spans = sorted_spans(store, annotation);
+            let multiple_resources = spans.windows(2).any(|pair| pair[0].0 != pair[1].0);
+            Ok(multiple_resources || !gaps(&spans).is_empty())
+        })
+    }
+
+    /// Returns the uncovered text in between the parts of a discontinuous annotation, as a tuple of TextSelections.
+    /// Only gaps between parts in the same resource are considered.
+    fn gaps<'py>(&self, py: Python<'py>) -> PyResult<&'py PyTuple> {
+        self.map_store(|store| {
+            let annotation: &Annotation = store.get(self.handle)?;
+            let mut elements: Vec<Py<PyTextSelection>> = Vec::new();
+            for (reshandle, begin, end) in gaps(&sorted_spans(store, annotation)) {
+                let resource: &TextResource = store.get(reshandle)?;
+                elements.push(
+                    Py::new(
+                        py,
+                        PyTextSelection {
+                            textselection: resource.textselection(&Offset::simple(begin, end))?,
+                            resource_handle: reshandle,
+                            store: self.store.clone(),
+                        },
+                    )
+                    .expect("Annotation.gaps() wrapping PyTextSelection"),
+                );
+            }
+            Ok(PyTuple::new(py, elements))
+        })
+    }
+
     /// Returns the annotations this annotation refers to (i.e. using an AnnotationSelector)
     /// They will be returned in a tuple.
     #[pyo3(signature = (recursive=false))]
@@ -131,28 +330,153 @@ impl PyAnnotation {
         })
     }
 
+    /// Returns the other annotations whose text spans overlap with the text spans of this annotation, in a tuple.
+    /// If a `key` is provided, only annotations with data for that key are returned, and if a `value` is provided as well,
+    /// the data must also have that value.
+    /// Unlike `annotations()`, this does not follow AnnotationSelectors but compares the actual text spans.
+    #[pyo3(signature = (key=None, value=None))]
+    fn overlapping<'py>(
+        &self,
+        key: Option<PyRef<PyDataKey>>,
+        value: Option<&PyAny>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let value = value
+            .map(py_into_datavalue)
+            .transpose()
+            .map_err(|err| PyStamError::new_err(format!("{}", err)))?;
+        let handles = self.map_store(|store| {
+            let annotation: &Annotation = store.get(self.handle)?;
+            let spans = sorted_spans(store, annotation);
+            let mut handles: Vec<AnnotationHandle> = Vec::new();
+            for reshandle in resource_handles(store, annotation) {
+                for handle in store
+                    .annotations_by_resource(reshandle)
+                    .into_iter()
+                    .flatten()
+                {
+                    if handle == self.handle || handles.contains(&handle) {
+                        continue;
+                    }
+                    let other: &Annotation = store.get(handle)?;
+                    let overlaps =
+                        sorted_spans(store, other)
+                            .iter()
+                            .any(|(otherres, obegin, oend)| {
+                                spans.iter().any(|(res, begin, end)| {
+                                    res == otherres && begin < oend && obegin < end
+                                })
+                            });
+                    if overlaps && has_data(store, other, key.as_deref(), value.as_ref()) {
+                        handles.push(handle);
+                    }
+                }
+            }
+            Ok(handles)
+        })?;
+        let elements: Vec<Py<PyAnnotation>> = handles
+            .into_iter()
+            .map(|handle| {
+                Py::new(
+                    py,
+                    PyAnnotation {
+                        handle,
+                        store: self.store.clone(),
+                    },
+                )
+                .expect("Annotation.overlapping() wrapping PyAnnotation")
+            })
+            .collect();
+        Ok(PyTuple::new(py, elements))
+    }
+
+    /// Finds the first occurrence of `fragment` in the text of this annotation and annotates it, with the given data,
+    /// using an AnnotationSelector with an offset relative to this annotation. Returns the new annotation,
+    /// or None if the fragment does not occur. The data is passed as to AnnotationStore.annotate().
+    /// This only works for annotations that target a single contiguous text selection.
+    #[pyo3(signature = (fragment, data, id=None))]
+    fn annotate_subspan(
+        &mut self,
+        fragment: &str,
+        data: Vec<&PyAny>,
+        id: Option<String>,
+    ) -> PyResult<Option<PyAnnotation>> {
+        let (offset, databuilders) = self.map_store(|store| {
+            let annotation: &Annotation = store.get(self.handle)?;
+            let mut textselections = store.textselections_by_annotation(annotation);
+            let offset = match (textselections.next(), textselections.next()) {
+                (Some((reshandle, textselection)), None) => {
+                    let resource: &TextResource = store.get(reshandle)?;
+                    let text = resource.text_of(&textselection.into())?;
+                    text.find(fragment).map(|bytepos| {
+                        let begin = text[..bytepos].chars().count();
+                        Offset::simple(begin, begin + fragment.chars().count())
+                    })
+                }
+                _ => {
+                    return Err(StamError::OtherError(
+                        "annotate_subspan() requires an annotation with a single contiguous text selection",
+                    ))
+                }
+            };
+            Ok((
+                offset,
+                data.iter()
+                    .map(|item| py_into_databuilder(item, store))
+                    .collect::<Vec<_>>(),
+            ))
+        })?;
+        let offset = match offset {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let mut builder = AnnotationBuilder::new()
+            .with_selector(Selector::AnnotationSelector(self.handle, Some(offset)));
+        if let Some(id) = id {
+            builder = builder.with_id(id);
+        }
+        for databuilder in databuilders {
+            builder = builder.with_data_builder(databuilder?);
+        }
+        let store = self.store.clone();
+        self.map_store_mut(|annotationstore| {
+            Ok(Some(PyAnnotation {
+                handle: annotationstore.annotate(builder)?,
+                store,
+            }))
+        })
+    }
+
     /// Returns the resources this annotation refers to
-    /// They will be returned in a tuple.
+    /// They will be returned in a tuple, without duplicates, in the order the resources were added to the store.
     fn resources<'py>(&self, py: Python<'py>) -> PyResult<&'py PyTuple> {
         self.map_store(|store| {
             let annotation: &Annotation = store.get(self.handle)?;
-            let elements: Vec<Py<PyTextResource>> = store
-                .resources_by_annotation(annotation)
-                .map(|targetitem| {
+            let elements: Vec<Py<PyTextResource>> = resource_handles(store, annotation)
+                .into_iter()
+                .map(|handle| {
                     Py::new(
                         py,
                         PyTextResource {
-                            handle: targetitem.handle().expect("must have handle"),
+                            handle,
                             store: self.store.clone(),
                         },
                     )
-                    .expect("Annotation.annotations() wrapping PyAnnotation")
+                    .expect("Annotation.resources() wrapping PyTextResource")
                 })
                 .collect();
             Ok(PyTuple::new(py, elements))
         })
     }
 
+    /// Returns the number of distinct resources this annotation refers to
+    fn resources_len(&self) -> PyResult<usize> {
+        self.map_store(|store| {
+            let annotation: &Annotation = store.get(self.handle)?;
+            Ok(resource_handles(store, annotation).len())
+        })
+    }
+
     /// Returns the resources this annotation refers to
     /// They will be returned in a tuple.
     fn annotationsets<'py>(&self, py: Python<'py>) -> PyResult<&'py PyTuple> {
@@ -176,6 +500,369 @@ impl PyAnnotation {
     }
 }
 
+/// Returns the text spans (resource, begin, end) an annotation refers to, sorted by resource and position
+pub(crate) fn sorted_spans(
+    store: &AnnotationStore,
+    annotation: &Annotation,
+) -> Vec<(TextResourceHandle, usize, usize)> {
+    let mut spans: Vec<(TextResourceHandle, usize, usize)> = store
+        .textselections_by_annotation(annotation)
+        .map(|(reshandle, textselection)| (reshandle, textselection.begin(), textselection.end()))
+        .collect();
+    spans.sort_unstable();
+    spans
+}
+
+/// A relation between two sets of text spans, as tested by the named predicates on Annotation and TextSelection
+#[derive(Clone, Copy)]
+pub(crate) enum SpanRelation {
+    Overlaps,
+    Embeds,
+    EmbeddedIn,
+    Precedes,
+    Succeeds,
+    Before,
+    After,
+}
+
+impl SpanRelation {
+    /// Tests whether the spans `a` relate to the spans `b` in this way. Spans in different resources never relate,
+    /// and empty sets of spans relate to nothing.
+    pub(crate) fn test(
+        self,
+        a: &[(TextResourceHandle, usize, usize)],
+        b: &[(TextResourceHandle, usize, usize)],
+    ) -> bool {
+        if a.is_empty() || b.is_empty() {
+            return false;
+        }
+        match self {
+            Self::Overlaps => a.iter().any(|(ares, abegin, aend)| {
+                b.iter()
+                    .any(|(bres, bbegin, bend)| ares == bres && abegin < bend && bbegin < aend)
+            }),
+            Self::Embeds => b.iter().all(|(bres, bbegin, bend)| {
+                a.iter()
+                    .any(|(ares, abegin, aend)| ares == bres && abegin <= bbegin && bend <= aend)
+            }),
+            Self::Before => a.iter().all(|(ares, _, aend)| {
+                b.iter()
+                    .all(|(bres, bbegin, _)| ares == bres && aend <= bbegin)
+            }),
+            Self::Precedes => {
+                Self::Before.test(a, b)
+                    && a.iter().map(|(_, _, end)| end).max()
+                        == b.iter().map(|(_, begin, _)| begin).min()
+            }
+            Self::EmbeddedIn => Self::Embeds.test(b, a),
+            Self::Succeeds => Self::Precedes.test(b, a),
+            Self::After => Self::Before.test(b, a),
+        }
+    }
+}
+
+/// Computes a 64-bit FNV-1a hash, which unlike Python's own `hash()` is stable across runs
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Computes the uncovered spans in between sorted spans, per resource
+fn gaps(spans: &[(TextResourceHandle, usize, usize)]) -> Vec<(TextResourceHandle, usize, usize)> {
+    let mut gaps = Vec::new();
+    let mut covered: Option<(TextResourceHandle, usize)> = None;
+    for (reshandle, begin, end) in spans.iter().copied() {
+        match covered {
+            Some((prevhandle, prevend)) if prevhandle == reshandle => {
+                if begin > prevend {
+                    gaps.push((reshandle, prevend, begin));
+                }
+                covered = Some((reshandle, prevend.max(end)));
+            }
+            _ => covered = Some((reshandle, end)),
+        }
+    }
+    gaps
+}
+
+/// Returns the handles of the resources an annotation refers to, sorted and without duplicates
+pub(crate) fn resource_handles(
+    store: &AnnotationStore,
+    annotation: &Annotation,
+) -> Vec<TextResourceHandle> {
+    let mut handles: Vec<TextResourceHandle> = store
+        .resources_by_annotation(annotation)
+        .map(|resource| resource.handle().expect("must have handle"))
+        .collect();
+    handles.sort_unstable();
+    handles.dedup();
+    handles
+}
+
+/// Tests whether an annotation has data for the given key (if any) with the given value (if any)
+pub(crate) fn has_data(
+    store: &AnnotationStore,
+    annotation: &Annotation,
+    key: Option<&PyDataKey>,
+    value: Option<&DataValue>,
+) -> bool {
+    if key.is_none() && value.is_none() {
+        return true;
+    }
+    resolve_data(store, annotation).any(|(set, data)| {
+        key.map(|key| set == key.set && data.key() == key.handle)
+            .unwrap_or(true)
+            && value.map(|value| data.value() == value).unwrap_or(true)
+    })
+}
+
+/// Iterates over the data of an annotation, resolving each to the actual AnnotationData (along with the handle of the set it is part of)
+pub(crate) fn resolve_data<'a>(
+    store: &'a AnnotationStore,
+    annotation: &'a Annotation,
+) -> impl Iterator<Item = (AnnotationDataSetHandle, &'a AnnotationData)> + 'a {
+    (0..)
+        .map_while(move |i| annotation.data_by_index(i))
+        .filter_map(move |(set, handle)| {
+            store
+                .annotationset(&(*set).into())
+                .and_then(|annotationset| annotationset.annotationdata(&(*handle).into()))
+                .map(|data| (*set, data))
+        })
+}
+
+#[pyclass(name = "AnnotationBuilder")]
+pub(crate) struct PyAnnotationBuilder {
+    pub(crate) id: Option<String>,
+    pub(crate) targets: Vec<PyObject>,
+    pub(crate) data: Vec<PyObject>,
+}
+
+#[pymethods]
+impl PyAnnotationBuilder {
+    #[new]
+    /// Holds a build recipe to build an Annotation, which can be passed to the annotate() function of the AnnotationStore.
+    /// The methods can be chained: `AnnotationBuilder().id("A1").target(selector).add_data(databuilder)`.
+    /// A builder is not consumed by annotate(), so it can be reused as a template.
+    fn new() -> Self {
+        PyAnnotationBuilder {
+            id: None,
+            targets: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
+    /// Sets the public ID of the annotation to build
+    fn id(mut slf: PyRefMut<Self>, id: String) -> PyRefMut<Self> {
+        slf.id = Some(id);
+        slf
+    }
+
+    /// Adds a target, anything accepted as target by annotate() may be passed.
+    /// If multiple targets are added, the annotation will get a MultiSelector.
+    fn target<'py>(mut slf: PyRefMut<'py, Self>, target: &PyAny) -> PyResult<PyRefMut<'py, Self>> {
+        py_into_selector(target)?; //validate early
+        slf.targets.push(target.into());
+        Ok(slf)
+    }
+
+    /// Adds data, anything accepted as data by annotate() may be passed:
+    /// an AnnotationDataBuilder, existing AnnotationData, or the public ID of existing AnnotationData.
+    fn add_data<'py>(mut slf: PyRefMut<'py, Self>, data: &PyAny) -> PyRefMut<'py, Self> {
+        slf.data.push(data.into());
+        slf
+    }
+}
+
+impl PyAnnotationBuilder {
+    /// Computes the selector for the annotation to build
+    pub(crate) fn selector(&self, py: Python) -> PyResult<Selector> {
+        match self.targets.as_slice() {
+            [] => Err(PyValueError::new_err("AnnotationBuilder has no target")),
+            [target] => py_into_selector(target.as_ref(py)),
+            targets => Ok(Selector::MultiSelector(
+                targets
+                    .iter()
+                    .map(|target| py_into_selector(target.as_ref(py)))
+                    .collect::<PyResult<Vec<_>>>()?,
+            )),
+        }
+    }
+}
+
+/// The content of an annotation expressed independently of any store: the text spans it targets (by resource ID)
+/// and its data (as annotation set ID, key ID and typed value), both sorted. Data that occurs multiple times is kept
+/// multiple times.
+pub(crate) type AnnotationContent = (
+    Vec<(Option<String>, usize, usize)>,
+    Vec<(Option<String>, Option<String>, TypedValue)>,
+);
+
+/// Describes a selector in a way that can be compared across stores, referring to everything by public ID.
+/// Returns `None` if anything the selector refers to has no public ID, as it can then not be identified across stores.
+fn selector_content(store: &AnnotationStore, selector: &Selector) -> Option<String> {
+    let resource_id = |handle: &TextResourceHandle| {
+        store
+            .resource(&(*handle).into())
+            .and_then(|resource| resource.id().map(|x| x.to_owned()))
+    };
+    Some(match selector {
+        Selector::ResourceSelector(handle) => {
+            format!("ResourceSelector({})", resource_id(handle)?)
+        }
+        Selector::TextSelector(handle, offset) => {
+            format!("TextSelector({}, {:?})", resource_id(handle)?, offset)
+        }
+        Selector::AnnotationSelector(handle, offset) => format!(
+            "AnnotationSelector({}, {:?})",
+            store
+                .annotation(&(*handle).into())
+                .and_then(|annotation| annotation.id().map(|x| x.to_owned()))?,
+            offset
+        ),
+        Selector::DataSetSelector(handle) => format!(
+            "DataSetSelector({})",
+            store
+                .annotationset(&(*handle).into())
+                .and_then(|annotationset| annotationset.id().map(|x| x.to_owned()))?
+        ),
+        Selector::MultiSelector(v)
+        | Selector::CompositeSelector(v)
+        | Selector::DirectionalSelector(v) => {
+            format!(
+                "{:?}[{}]",
+                selector.kind(),
+                v.iter()
+                    .map(|subselector| selector_content(store, subselector))
+                    .collect::<Option<Vec<_>>>()?
+                    .join(", ")
+            )
+        }
+        //internal selectors are described like the public selectors they stand for, so they compare equal to those
+        Selector::InternalTextSelector {
+            resource,
+            textselection,
+        } => {
+            let textselection: &TextSelection = store
+                .resource(&(*resource).into())
+                .and_then(|resource| resource.get(*textselection).ok())?;
+            format!(
+                "TextSelector({}, {:?})",
+                resource_id(resource)?,
+                Offset::simple(textselection.begin(), textselection.end())
+            )
+        }
+        Selector::InternalAnnotationTextSelector {
+            annotation,
+            resource,
+            textselection,
+        } => {
+            let textselection: &TextSelection = store
+                .resource(&(*resource).into())
+                .and_then(|resource| resource.get(*textselection).ok())?;
+            format!(
+                "AnnotationTextSelector({}, {}, {:?})",
+                store
+                    .annotation(&(*annotation).into())
+                    .and_then(|annotation| annotation.id().map(|x| x.to_owned()))?,
+                resource_id(resource)?,
+                Offset::simple(textselection.begin(), textselection.end())
+            )
+        }
+        //ranged selectors are described as the sequence of selectors they compress
+        Selector::InternalRangedResourceSelector { .. } => {
+            TargetIter::<TextResource>::new(selector.iter(store, false, false))
+                .map(|resource| resource.id().map(|id| format!("ResourceSelector({})", id)))
+                .collect::<Option<Vec<_>>>()?
+                .join(", ")
+        }
+        Selector::InternalRangedAnnotationSelector { .. } => {
+            TargetIter::<Annotation>::new(selector.iter(store, false, false))
+                .map(|annotation| {
+                    annotation
+                        .id()
+                        .map(|id| format!("AnnotationSelector({}, None)", id))
+                })
+                .collect::<Option<Vec<_>>>()?
+                .join(", ")
+        }
+        Selector::InternalRangedDataSetSelector { .. } => {
+            TargetIter::<AnnotationDataSet>::new(selector.iter(store, false, false))
+                .map(|annotationset| {
+                    annotationset
+                        .id()
+                        .map(|id| format!("DataSetSelector({})", id))
+                })
+                .collect::<Option<Vec<_>>>()?
+                .join(", ")
+        }
+        Selector::InternalRangedTextSelector { resource, .. } => {
+            let resource_id = resource_id(resource)?;
+            TargetIter::<TextSelection>::new(selector.iter(store, false, false))
+                .map(|textselection| {
+                    format!(
+                        "TextSelector({}, {:?})",
+                        resource_id,
+                        Offset::simple(textselection.begin(), textselection.end())
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    })
+}
+
+/// Extracts the selector and data of an annotation for `equivalent()`, returns `None` if anything it refers to has no public ID
+#[allow(clippy::type_complexity)]
+fn equivalence_content(
+    store: &AnnotationStore,
+    annotation: &Annotation,
+) -> Option<(String, Vec<(Option<String>, Option<String>, TypedValue)>)> {
+    let selector = selector_content(store, annotation.target())?;
+    let data = annotation_content(store, annotation).1;
+    if data
+        .iter()
+        .any(|(set, key, _)| set.is_none() || key.is_none())
+    {
+        return None;
+    }
+    Some((selector, data))
+}
+
+/// Extracts the content of an annotation in a way that can be compared across stores
+pub(crate) fn annotation_content(
+    store: &AnnotationStore,
+    annotation: &Annotation,
+) -> AnnotationContent {
+    let spans = sorted_spans(store, annotation)
+        .into_iter()
+        .map(|(reshandle, begin, end)| {
+            (
+                store
+                    .resource(&reshandle.into())
+                    .and_then(|resource| resource.id().map(|x| x.to_owned())),
+                begin,
+                end,
+            )
+        })
+        .collect();
+    let mut data: Vec<_> = resolve_data(store, annotation)
+        .map(|(set, data)| {
+            let annotationset = store.annotationset(&set.into());
+            (
+                annotationset.and_then(|annotationset| annotationset.id().map(|x| x.to_owned())),
+                annotationset
+                    .and_then(|annotationset| annotationset.key(&data.key().into()))
+                    .and_then(|key| key.id().map(|x| x.to_owned())),
+                TypedValue(data.value().clone()),
+            )
+        })
+        .collect();
+    data.sort_unstable();
+    (spans, data)
+}
+
 #[pyclass(name = "DataIter")]
 struct PyDataIter {
     pub(crate) handle: AnnotationHandle,
@@ -204,6 +891,38 @@ impl PyDataIter {
             }
         })
     }
+
+    /// Consumes the remaining data and returns it as a plain list, decoupled from the live state of the store.
+    /// This is the recommended pattern if you want to modify the store while processing the data.
+    fn snapshot<'py>(&mut self, py: Python<'py>) -> &'py PyList {
+        let start = self.index;
+        let data: Vec<(AnnotationDataSetHandle, AnnotationDataHandle)> = self
+            .map(|annotation| {
+                Some(
+                    (start..)
+                        .map_while(|i| annotation.data_by_index(i))
+                        .copied()
+                        .collect(),
+                )
+            })
+            .unwrap_or_default();
+        self.index += data.len();
+        let elements: Vec<Py<PyAnnotationData>> = data
+            .into_iter()
+            .map(|(set, handle)| {
+                Py::new(
+                    py,
+                    PyAnnotationData {
+                        set,
+                        handle,
+                        store: self.store.clone(),
+                    },
+                )
+                .expect("wrapping PyAnnotationData")
+            })
+            .collect();
+        PyList::new(py, elements)
+    }
 }
 
 impl PyDataIter {
@@ -233,6 +952,40 @@ impl MapStore for PyAnnotation {
 }
 
 impl PyAnnotation {
+    /// Tests how the text spans of this annotation relate to those of another one
+    fn test_relation(&self, other: &PyAnnotation, relation: SpanRelation) -> PyResult<bool> {
+        self.check_same_store(other)?;
+        self.map_store(|store| {
+            let annotation: &Annotation = store.get(self.handle)?;
+            let other: &Annotation = store.get(other.handle)?;
+            Ok(relation.test(
+                &sorted_spans(store, annotation),
+                &sorted_spans(store, other),
+            ))
+        })
+    }
+
+    /// Returns the data (as set and data handles) this annotation has in common with another one
+    fn common_data_handles(
+        &self,
+        other: &PyAnnotation,
+    ) -> PyResult<Vec<(AnnotationDataSetHandle, AnnotationDataHandle)>> {
+        self.check_same_store(other)?;
+        self.map_store(|store| {
+            let annotation: &Annotation = store.get(self.handle)?;
+            let other: &Annotation = store.get(other.handle)?;
+            let otherdata: HashSet<(AnnotationDataSetHandle, AnnotationDataHandle)> = (0..)
+                .map_while(|i| other.data_by_index(i))
+                .copied()
+                .collect();
+            Ok((0..)
+                .map_while(|i| annotation.data_by_index(i))
+                .copied()
+                .filter(|data| otherdata.contains(data))
+                .collect())
+        })
+    }
+
     /// Map function to act on the actual underlying store, helps reduce boilerplate
     fn map<T, F>(&self, f: F) -> Result<T, PyErr>
     where
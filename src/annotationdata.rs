@@ -1,8 +1,14 @@
-use pyo3::exceptions::{PyException, PyIndexError, PyKeyError, PyRuntimeError, PyValueError};
+use pyo3::exceptions::{
+    PyException, PyIndexError, PyKeyError, PyRuntimeError, PyTypeError, PyValueError,
+};
 use pyo3::prelude::*;
 use pyo3::pyclass::CompareOp;
 use pyo3::types::*;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
 use std::fmt::Display;
+use std::hash::{Hash, Hasher};
 use std::ops::FnOnce;
 use std::sync::{Arc, RwLock};
 
@@ -82,6 +88,27 @@ impl PyDataKey {
         })
     }
 
+    /// Returns the set of DataValue types (`String`, `Int`, `Float`, `Bool`, `List` or `Null`) actually used in the data
+    /// for this key. More than one type for a single key usually indicates a data quality issue.
+    fn value_types(&self) -> PyResult<HashSet<&'static str>> {
+        self.map_store(|store| {
+            let annotationset: &AnnotationDataSet = store.get(self.set)?;
+            let mut types = HashSet::new();
+            for handle in annotationset.data_by_key(self.handle).into_iter().flatten() {
+                let annotationdata: &AnnotationData = annotationset.get(*handle)?;
+                types.insert(match annotationdata.value() {
+                    DataValue::String(_) => "String",
+                    DataValue::Int(_) => "Int",
+                    DataValue::Float(_) => "Float",
+                    DataValue::Bool(_) => "Bool",
+                    DataValue::List(_) => "List",
+                    DataValue::Null => "Null",
+                });
+            }
+            Ok(types)
+        })
+    }
+
     /// Find annotation data for the current key and specified value
     /// Returns an AnnotationData instance if found, None otherwise
     /// Use AnnotationDataSet.find_data() instead if you don't have a DataKey instance yet.
@@ -141,15 +168,28 @@ pub(crate) struct PyAnnotationData {
     pub(crate) store: Arc<RwLock<AnnotationStore>>,
 }
 
+/// Converts a Python object into a DataValue. The type is determined in the following order:
+/// numpy scalars are first converted to their native Python type, then `bool` becomes Bool (checked before
+/// integers as `bool` is a subclass of `int` in Python), `str` becomes String, `int` becomes Int, `float` becomes Float
+/// (also if it is integral, e.g. `3.0`), `None` becomes Null and a `list` becomes a List (converting each item).
+/// Strings are never parsed, use `PyDataValue::coerce()` to force a particular type.
 pub(crate) fn py_into_datavalue<'py>(value: &'py PyAny) -> Result<DataValue, StamError> {
-    if let Ok(value) = value.extract() {
+    if value.hasattr("dtype").unwrap_or(false) && value.hasattr("item").unwrap_or(false) {
+        //duck-typing for numpy scalars (np.int64, np.float32, np.bool_, etc), item() converts them to the native python type
+        if let Ok(item) = value.call_method0("item") {
+            if !item.hasattr("dtype").unwrap_or(false) {
+                return py_into_datavalue(item);
+            }
+        }
+    }
+    if let Ok(value) = value.downcast::<PyBool>() {
+        Ok(DataValue::Bool(value.is_true()))
+    } else if let Ok(value) = value.extract() {
         Ok(DataValue::String(value))
     } else if let Ok(value) = value.extract() {
         Ok(DataValue::Int(value))
     } else if let Ok(value) = value.extract() {
         Ok(DataValue::Float(value))
-    } else if let Ok(value) = value.extract() {
-        Ok(DataValue::Bool(value))
     } else if let Ok(None) = value.extract::<Option<bool>>() {
         Ok(DataValue::Null)
     } else {
@@ -168,6 +208,55 @@ pub(crate) fn py_into_datavalue<'py>(value: &'py PyAny) -> Result<DataValue, Sta
     }
 }
 
+/// Converts a Python object into an AnnotationDataBuilder. Accepts an AnnotationDataBuilder, an existing AnnotationData,
+/// or a string holding the public ID of existing AnnotationData (which may be in any annotation set).
+pub(crate) fn py_into_databuilder(
+    item: &PyAny,
+    store: &AnnotationStore,
+) -> PyResult<AnnotationDataBuilder> {
+    if let Ok(databuilder) = item.extract::<PyRef<PyAnnotationDataBuilder>>() {
+        Ok(databuilder.builder.clone())
+    } else if let Ok(annotationdata) = item.extract::<PyRef<PyAnnotationData>>() {
+        let mut builder = AnnotationDataBuilder::default();
+        builder.id = AnyId::Handle(annotationdata.handle);
+        builder.annotationset = AnyId::Handle(annotationdata.set);
+        Ok(builder)
+    } else if let Ok(id) = item.extract::<&str>() {
+        let mut found: Option<(AnnotationDataSetHandle, AnnotationDataHandle)> = None;
+        for annotationset in
+            (0..store.annotationsets_len()).filter_map(|i| store.annotationset_by_index(i))
+        {
+            if let Ok(handle) = annotationset.resolve_data_id(id) {
+                if found.is_some() {
+                    return Err(PyStamError::new_err(format!(
+                        "AnnotationData ID {} is ambiguous, it occurs in multiple annotation sets, use AnnotationDataBuilder.link() instead",
+                        id
+                    )));
+                }
+                found = Some((
+                    annotationset.handle().expect("set must have handle"),
+                    handle,
+                ));
+            }
+        }
+        if let Some((set, handle)) = found {
+            let mut builder = AnnotationDataBuilder::default();
+            builder.id = AnyId::Handle(handle);
+            builder.annotationset = AnyId::Handle(set);
+            Ok(builder)
+        } else {
+            Err(PyStamError::new_err(format!(
+                "No AnnotationData with ID {} found",
+                id
+            )))
+        }
+    } else {
+        Err(PyValueError::new_err(
+            "Data must be an AnnotationDataBuilder, AnnotationData or the ID of existing AnnotationData",
+        ))
+    }
+}
+
 pub(crate) fn datavalue_into_py<'py>(
     datavalue: &DataValue,
     py: Python<'py>,
@@ -193,6 +282,119 @@ pub(crate) fn datavalue_into_py<'py>(
     }
 }
 
+/// Converts a float to an integer only if it has no fractional part and is within range of an integer
+fn float_as_int(value: f64) -> Option<isize> {
+    //isize::MAX as f64 rounds up to 2^63, which is itself out of range, hence the exclusive upper bound
+    if value.fract() == 0.0 && value >= isize::MIN as f64 && value < isize::MAX as f64 {
+        Some(value as isize)
+    } else {
+        None
+    }
+}
+
+/// Compares an integer with a float exactly, without losing precision by converting the integer to a float
+fn cmp_int_float(a: isize, b: f64) -> Option<Ordering> {
+    match float_as_int(b) {
+        Some(b) => Some(a.cmp(&b)),
+        //integral or infinite, but out of range of any integer
+        None if b.fract() == 0.0 || b.is_infinite() => Some(if b > 0.0 {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        }),
+        None => (a as f64).partial_cmp(&b),
+    }
+}
+
+/// Hashes a value along with its type. Floats without a fractional part hash like the equal integer (this also
+/// makes `0.0` and `-0.0` hash alike), consistent with equality in `DataValue.__richcmp__()`.
+fn hash_datavalue<H: Hasher>(value: &DataValue, hasher: &mut H) {
+    match value {
+        DataValue::String(s) => (0u8, s).hash(hasher),
+        DataValue::Int(v) => (1u8, v).hash(hasher),
+        DataValue::Float(v) => match float_as_int(*v) {
+            Some(v) => (1u8, v).hash(hasher),
+            None => (2u8, v.to_bits()).hash(hasher),
+        },
+        DataValue::Bool(v) => (3u8, v).hash(hasher),
+        DataValue::Null => 4u8.hash(hasher),
+        DataValue::List(v) => {
+            5u8.hash(hasher);
+            for item in v.iter() {
+                hash_datavalue(item, hasher);
+            }
+        }
+    }
+}
+
+/// Ranks the types of values, for ordering values of different types
+fn datavalue_type_rank(value: &DataValue) -> u8 {
+    match value {
+        DataValue::String(_) => 0,
+        DataValue::Int(_) => 1,
+        DataValue::Float(_) => 2,
+        DataValue::Bool(_) => 3,
+        DataValue::Null => 4,
+        DataValue::List(_) => 5,
+    }
+}
+
+/// Orders values by type first and then by value, floats are ordered by `total_cmp()`
+fn datavalue_cmp(value: &DataValue, other: &DataValue) -> Ordering {
+    match (value, other) {
+        (DataValue::String(a), DataValue::String(b)) => a.cmp(b),
+        (DataValue::Int(a), DataValue::Int(b)) => a.cmp(b),
+        (DataValue::Float(a), DataValue::Float(b)) => a.total_cmp(b),
+        (DataValue::Bool(a), DataValue::Bool(b)) => a.cmp(b),
+        (DataValue::List(a), DataValue::List(b)) => a
+            .iter()
+            .zip(b.iter())
+            .map(|(x, y)| datavalue_cmp(x, y))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        _ => datavalue_type_rank(value).cmp(&datavalue_type_rank(other)),
+    }
+}
+
+/// A value that is compared by type and value with a total order, so values can be sorted, hashed and compared
+/// across stores without conflating values of different types (e.g. `1`, `1.0` and `"1"`)
+#[derive(Clone, Debug)]
+pub(crate) struct TypedValue(pub(crate) DataValue);
+
+impl Ord for TypedValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        datavalue_cmp(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for TypedValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for TypedValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for TypedValue {}
+
+impl Hash for TypedValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_datavalue(&self.0, state)
+    }
+}
+
+impl ToPyObject for TypedValue {
+    fn to_object(&self, py: Python<'_>) -> PyObject {
+        datavalue_into_py(&self.0, py)
+            .map(|value| value.to_object(py))
+            .unwrap_or_else(|_| py.None())
+    }
+}
+
 #[pyclass(name = "DataValue")]
 #[derive(Clone, Debug)]
 /// Encapsulates a value and its type. Held by `AnnotationData`. This type is not a reference but holds the actual value.
@@ -221,18 +423,117 @@ impl PyDataValue {
         })
     }
 
-    fn __richcmp__(&self, other: PyRef<Self>, op: CompareOp) -> Py<PyAny> {
+    /// Values can be tested for (in)equality regardless of their types. Numbers (integers and floats alike) and strings
+    /// can also be ordered amongst themselves; ordering other combinations raises a TypeError.
+    /// Equality is consistent with that ordering: an integer and a float with the same numeric value are equal (and
+    /// hash alike), as in Python itself.
+    fn __richcmp__(&self, other: PyRef<Self>, op: CompareOp) -> PyResult<Py<PyAny>> {
         let py = other.py();
-        match op {
-            CompareOp::Eq => (self.value == other.value).into_py(py),
-            CompareOp::Ne => (self.value != other.value).into_py(py),
-            _ => py.NotImplemented(),
+        let ordering = match op {
+            CompareOp::Eq => return Ok(self.equals(&other.value).into_py(py)),
+            CompareOp::Ne => return Ok((!self.equals(&other.value)).into_py(py)),
+            _ => self.ordering(&other.value).ok_or_else(|| {
+                PyTypeError::new_err(format!("Values {} and {} can not be ordered", self, other))
+            })?,
+        };
+        Ok(match op {
+            CompareOp::Lt => ordering.is_lt(),
+            CompareOp::Le => ordering.is_le(),
+            CompareOp::Gt => ordering.is_gt(),
+            _ => ordering.is_ge(),
         }
+        .into_py(py))
+    }
+
+    /// Values are hashed by type and value, consistent with equality, so they can be used as dictionary keys
+    fn __hash__(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hash_datavalue(&self.value, &mut hasher);
+        hasher.finish()
     }
 
     fn __str__(&self) -> String {
         self.to_string()
     }
+
+    /// Returns the value as an integer, coercing it if needed: strings are parsed and floats are only accepted if
+    /// they have no fractional part and are within range of an integer. Raises a ValueError if coercion fails, unless a `default` is provided.
+    #[pyo3(signature = (default=None))]
+    fn as_int(&self, default: Option<isize>) -> PyResult<isize> {
+        let value = match &self.value {
+            DataValue::Int(v) => Some(*v),
+            DataValue::Float(v) => float_as_int(*v),
+            DataValue::String(s) => s.trim().parse().ok(),
+            _ => None,
+        };
+        value
+            .or(default)
+            .ok_or_else(|| self.coercion_error("an integer"))
+    }
+
+    /// Returns the value as a float, coercing it if needed: integers are converted and strings are parsed.
+    /// Raises a ValueError if coercion fails, unless a `default` is provided.
+    #[pyo3(signature = (default=None))]
+    fn as_float(&self, default: Option<f64>) -> PyResult<f64> {
+        let value = match &self.value {
+            DataValue::Float(v) => Some(*v),
+            DataValue::Int(v) => Some(*v as f64),
+            DataValue::String(s) => s.trim().parse().ok(),
+            _ => None,
+        };
+        value
+            .or(default)
+            .ok_or_else(|| self.coercion_error("a float"))
+    }
+
+    /// Returns the value as a string, numbers and booleans are converted to their string representation.
+    /// Raises a ValueError if coercion fails, unless a `default` is provided.
+    #[pyo3(signature = (default=None))]
+    fn as_str(&self, default: Option<String>) -> PyResult<String> {
+        let value = match &self.value {
+            DataValue::String(s) => Some(s.clone()),
+            DataValue::Int(_) | DataValue::Float(_) | DataValue::Bool(_) => Some(self.to_string()),
+            _ => None,
+        };
+        value
+            .or(default)
+            .ok_or_else(|| self.coercion_error("a string"))
+    }
+
+    /// Returns the value as a boolean, coercing it if needed: the strings "true"/"false" (case insensitive) and
+    /// the integers 1/0 are accepted. Raises a ValueError if coercion fails, unless a `default` is provided.
+    #[pyo3(signature = (default=None))]
+    fn as_bool(&self, default: Option<bool>) -> PyResult<bool> {
+        let value = match &self.value {
+            DataValue::Bool(v) => Some(*v),
+            DataValue::Int(1) => Some(true),
+            DataValue::Int(0) => Some(false),
+            DataValue::String(s) => match s.trim().to_lowercase().as_str() {
+                "true" => Some(true),
+                "false" => Some(false),
+                _ => None,
+            },
+            _ => None,
+        };
+        value
+            .or(default)
+            .ok_or_else(|| self.coercion_error("a boolean"))
+    }
+
+    /// Tests whether the value is a number (an integer or a float)
+    fn is_numeric(&self) -> bool {
+        matches!(self.value, DataValue::Int(_) | DataValue::Float(_))
+    }
+
+    /// Tests whether the value is a string
+    fn is_string(&self) -> bool {
+        matches!(self.value, DataValue::String(_))
+    }
+
+    /// Tests whether the value is a list
+    fn is_list(&self) -> bool {
+        matches!(self.value, DataValue::List(_))
+    }
 }
 
 impl PyDataValue {
@@ -245,6 +546,45 @@ impl PyDataValue {
     fn test(&self, other: &DataValue) -> bool {
         self.value == *other
     }
+
+    /// Coerces the value to the specified type ("str", "int", "float" or "bool"), see the `as_*()` methods for the rules
+    pub(crate) fn coerce(&self, target: &str) -> PyResult<DataValue> {
+        match target {
+            "str" => self.as_str(None).map(DataValue::String),
+            "int" => self.as_int(None).map(DataValue::Int),
+            "float" => self.as_float(None).map(DataValue::Float),
+            "bool" => self.as_bool(None).map(DataValue::Bool),
+            _ => Err(PyValueError::new_err(
+                "coerce must be \"str\", \"int\", \"float\" or \"bool\"",
+            )),
+        }
+    }
+
+    /// Tests for equality, numbers compare by numeric value regardless of whether they are integers or floats
+    fn equals(&self, other: &DataValue) -> bool {
+        match self.ordering(other) {
+            Some(ordering) => ordering.is_eq(),
+            None => self.value == *other,
+        }
+    }
+
+    /// Orders numbers (integers and floats alike) and strings amongst themselves, other combinations can not be ordered
+    fn ordering(&self, other: &DataValue) -> Option<Ordering> {
+        match (&self.value, other) {
+            (DataValue::Int(a), DataValue::Int(b)) => Some(a.cmp(b)),
+            (DataValue::Int(a), DataValue::Float(b)) => cmp_int_float(*a, *b),
+            (DataValue::Float(a), DataValue::Int(b)) => {
+                cmp_int_float(*b, *a).map(Ordering::reverse)
+            }
+            (DataValue::Float(a), DataValue::Float(b)) => a.partial_cmp(b),
+            (DataValue::String(a), DataValue::String(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+
+    fn coercion_error(&self, target: &str) -> PyErr {
+        PyValueError::new_err(format!("Unable to coerce value {} to {}", self, target))
+    }
 }
 
 //not sure if we really need these from implementations here
@@ -422,7 +762,7 @@ impl PyAnnotationDataBuilder {
     ///
     /// If you already have existing AnnotationData or DataKey objects, then consider
     /// using the `link()` respectively `link_key()` static methods instead, as those will be quicker.
-    fn new(
+    pub(crate) fn new(
         annotationset: String,
         key: String,
         value: &PyAny,
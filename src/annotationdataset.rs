@@ -2,10 +2,15 @@ use pyo3::exceptions::{PyException, PyIndexError, PyKeyError, PyRuntimeError, Py
 use pyo3::prelude::*;
 use pyo3::pyclass::CompareOp;
 use pyo3::types::*;
+use std::collections::HashMap;
 use std::ops::FnOnce;
 use std::sync::{Arc, RwLock};
 
-use crate::annotationdata::{py_into_datavalue, PyAnnotationData, PyDataKey, PyDataValue};
+use crate::annotation::PyAnnotation;
+use crate::annotationdata::{
+    datavalue_into_py, py_into_datavalue, PyAnnotationData, PyDataKey, PyDataValue,
+};
+use crate::annotationstore::MapStore;
 use crate::error::PyStamError;
 use crate::selector::PySelector;
 use stam::*;
@@ -80,20 +85,29 @@ impl PyAnnotationDataSet {
         self.map(|store| Ok(store.data_len()))
     }
 
-    /// Create a new AnnotationData instance and adds it to the dataset
+    /// Create a new AnnotationData instance and adds it to the dataset.
+    /// If `coerce` is set to "str", "int", "float" or "bool", the value is converted to that type first (e.g. the string
+    /// "1" to the integer 1), raising a ValueError if that is not possible.
+    #[pyo3(signature = (key, value, id=None, coerce=None))]
     fn add_data<'py>(
         &self,
         key: &str,
         value: &'py PyAny,
         id: Option<&str>,
+        coerce: Option<&str>,
     ) -> PyResult<PyAnnotationData> {
+        let value =
+            py_into_datavalue(value).map_err(|err| PyStamError::new_err(format!("{}", err)))?;
+        let value = match coerce {
+            Some(coerce) => PyDataValue { value }.coerce(coerce)?,
+            None => value,
+        };
         let datakey = if let Ok(datakey) = self.key(key) {
             datakey
         } else {
             self.add_key(key)?
         };
         self.map_mut(|annotationset| {
-            let value = py_into_datavalue(value)?;
             let datakey = AnnotationData::new(id.map(|x| x.to_string()), datakey.handle, value);
             let handle = annotationset.insert(datakey)?;
             Ok(PyAnnotationData {
@@ -104,6 +118,87 @@ impl PyAnnotationDataSet {
         })
     }
 
+    /// Returns a tuple of all annotations that use data from this set, or that target this set as a whole via a DataSetSelector.
+    /// If a `key` is provided, only annotations with data for that key (in this set) are returned, and if a `value` is provided
+    /// as well, the data must also have that value. Annotations are found via the reverse index of the data, so the cost of a
+    /// filtered lookup depends on the matching data rather than the size of the store.
+    #[pyo3(signature = (key=None, value=None))]
+    fn annotations<'py>(
+        &self,
+        key: Option<&str>,
+        value: Option<&PyAny>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let key = match key {
+            Some(key) => match self.key(key) {
+                Ok(key) => Some(key),
+                //an unknown key can't match anything
+                Err(_) => return Ok(PyTuple::empty(py)),
+            },
+            None => None,
+        };
+        let value = value
+            .map(py_into_datavalue)
+            .transpose()
+            .map_err(|err| PyStamError::new_err(format!("{}", err)))?;
+        let handles = self.map_store(|store| {
+            let annotationset: &AnnotationDataSet = store.get(self.handle)?;
+            let datahandles: Vec<AnnotationDataHandle> = match key.as_ref() {
+                Some(key) => annotationset
+                    .data_by_key(key.handle)
+                    .cloned()
+                    .unwrap_or_default(),
+                None => (0..annotationset.data_len())
+                    .map(AnnotationDataHandle::new)
+                    .collect(),
+            };
+            let mut handles: Vec<AnnotationHandle> = Vec::new();
+            for datahandle in datahandles {
+                if let Some(value) = value.as_ref() {
+                    match annotationset.annotationdata(&datahandle.into()) {
+                        Some(data) if data.value() == value => {}
+                        _ => continue,
+                    }
+                }
+                handles.extend(
+                    store
+                        .annotations_by_data(self.handle, datahandle)
+                        .into_iter()
+                        .flatten()
+                        .copied(),
+                );
+            }
+            if key.is_none() && value.is_none() {
+                //there is no reverse index for annotations that target the set as a whole, so this remains a scan
+                handles.extend(
+                    (0..store.annotations_len())
+                        .filter_map(|i| store.annotation_by_index(i))
+                        .filter(|annotation| {
+                            matches!(annotation.target(), Selector::DataSetSelector(set) if *set == self.handle)
+                        })
+                        .map(|annotation| annotation.handle().expect("annotation must have a handle")),
+                );
+            }
+            handles.sort_unstable();
+            handles.dedup();
+            Ok(handles)
+        })?;
+        let elements: Vec<Py<PyAnnotation>> = handles
+            .into_iter()
+            .map(|handle| {
+                Py::new(
+                    py,
+                    PyAnnotation {
+                        handle,
+                        store: self.store.clone(),
+                    },
+                )
+                .expect("wrapping PyAnnotation")
+            })
+            .collect();
+        Ok(PyTuple::new(py, elements))
+    }
+
     /// Get a AnnotationData instance by id, raises an exception if not found
     fn annotationdata(&self, data_id: &str) -> PyResult<PyAnnotationData> {
         self.map(|annotationset| {
@@ -140,6 +235,107 @@ impl PyAnnotationDataSet {
         self.map(|set| set.selector().map(|sel| sel.into()))
     }
 
+    /// Validates the data in this set against a schema that declares the allowed keys and values, as in a controlled vocabulary.
+    /// The schema is a dictionary mapping key IDs to a specification, which is either None (any value is allowed)
+    /// or a dictionary with any of the following:
+    /// * `type`: the name of the allowed type, or a list of names: "str", "int", "float", "bool", "list" or "null"
+    /// * `min` and `max`: the allowed (inclusive) range for numeric values
+    /// * `values`: a list of allowed values
+    ///
+    /// Returns a list of violations, each a dictionary with the offending `data`, its `key` (ID), its `value`, a `message`,
+    /// and the `annotations` (a tuple) that use the offending data.
+    fn validate_against<'py>(&self, schema: &PyDict, py: Python<'py>) -> PyResult<&'py PyList> {
+        let mut specs: HashMap<String, Option<ValueSpec>> = HashMap::new();
+        for (key, spec) in schema.iter() {
+            let key: String = key.extract()?;
+            let spec = if spec.is_none() {
+                None
+            } else {
+                let spec: &PyDict = spec.downcast().map_err(|_| {
+                    PyValueError::new_err(format!(
+                        "Schema for key {} must be a dictionary or None",
+                        key
+                    ))
+                })?;
+                Some(ValueSpec::from_py(spec)?)
+            };
+            specs.insert(key, spec);
+        }
+        let violations = self.map_store(|store| {
+            let annotationset: &AnnotationDataSet = store.get(self.handle)?;
+            let mut violations = Vec::new();
+            for i in 0..annotationset.data_len() {
+                let data = match annotationset.annotationdata(&AnnotationDataHandle::new(i).into())
+                {
+                    Some(data) => data,
+                    None => continue,
+                };
+                let key: Option<&str> = annotationset
+                    .key(&data.key().into())
+                    .and_then(|key| key.id());
+                let message = match key.map(|key| specs.get(key)) {
+                    None => Some(
+                        "key has no public ID, so it can not be checked against the schema"
+                            .to_string(),
+                    ),
+                    Some(None) => Some("key is not declared in the schema".to_string()),
+                    Some(Some(None)) => None,
+                    Some(Some(Some(spec))) => spec.validate(data.value()),
+                };
+                if let Some(message) = message {
+                    violations.push((
+                        AnnotationDataHandle::new(i),
+                        key.map(|x| x.to_owned()),
+                        data.value().clone(),
+                        message,
+                        store
+                            .annotations_by_data(self.handle, AnnotationDataHandle::new(i))
+                            .cloned()
+                            .unwrap_or_default(),
+                    ));
+                }
+            }
+            Ok(violations)
+        })?;
+        let result = PyList::empty(py);
+        for (handle, key, value, message, annotations) in violations {
+            let violation = PyDict::new(py);
+            violation.set_item(
+                "data",
+                Py::new(
+                    py,
+                    PyAnnotationData {
+                        handle,
+                        set: self.handle,
+                        store: self.store.clone(),
+                    },
+                )?,
+            )?;
+            violation.set_item("key", key)?;
+            violation.set_item(
+                "value",
+                datavalue_into_py(&value, py)
+                    .map_err(|err| PyStamError::new_err(format!("{}", err)))?,
+            )?;
+            violation.set_item("message", message)?;
+            let annotations: Vec<Py<PyAnnotation>> = annotations
+                .into_iter()
+                .map(|handle| {
+                    Py::new(
+                        py,
+                        PyAnnotation {
+                            handle,
+                            store: self.store.clone(),
+                        },
+                    )
+                })
+                .collect::<PyResult<_>>()?;
+            violation.set_item("annotations", PyTuple::new(py, annotations))?;
+            result.append(violation)?;
+        }
+        Ok(result)
+    }
+
     /// Find annotation data by key and value
     /// Returns an AnnotationData instance if found, None otherwise
     fn find_data(&self, key: &str, value: &PyAny) -> PyResult<Option<PyAnnotationData>> {
@@ -196,6 +392,109 @@ impl PyAnnotationDataSet {
     }
 }
 
+impl MapStore for PyAnnotationDataSet {
+    fn get_store(&self) -> &Arc<RwLock<AnnotationStore>> {
+        &self.store
+    }
+    fn get_store_mut(&mut self) -> &mut Arc<RwLock<AnnotationStore>> {
+        &mut self.store
+    }
+}
+
+/// Specification of the allowed values for a key, as used by `validate_against()`
+struct ValueSpec {
+    types: Option<Vec<String>>,
+    min: Option<f64>,
+    max: Option<f64>,
+    values: Option<Vec<DataValue>>,
+}
+
+impl ValueSpec {
+    fn from_py(spec: &PyDict) -> PyResult<Self> {
+        let types = match spec.get_item("type") {
+            None => None,
+            Some(types) => {
+                let types: Vec<String> = if let Ok(name) = types.extract::<String>() {
+                    vec![name]
+                } else {
+                    types.extract()?
+                };
+                for name in types.iter() {
+                    if !["str", "int", "float", "bool", "list", "null"].contains(&name.as_str()) {
+                        return Err(PyValueError::new_err(format!(
+                            "Unknown type in schema: {}",
+                            name
+                        )));
+                    }
+                }
+                Some(types)
+            }
+        };
+        let values = match spec.get_item("values") {
+            None => None,
+            Some(values) => {
+                let values: &PyList = values.downcast().map_err(|_| {
+                    PyValueError::new_err("Allowed values in schema must be a list")
+                })?;
+                Some(
+                    values
+                        .iter()
+                        .map(|value| {
+                            py_into_datavalue(value)
+                                .map_err(|err| PyStamError::new_err(format!("{}", err)))
+                        })
+                        .collect::<PyResult<Vec<_>>>()?,
+                )
+            }
+        };
+        Ok(Self {
+            types,
+            min: spec.get_item("min").map(|x| x.extract()).transpose()?,
+            max: spec.get_item("max").map(|x| x.extract()).transpose()?,
+            values,
+        })
+    }
+
+    /// Returns a message describing the violation if the value does not meet the specification
+    fn validate(&self, value: &DataValue) -> Option<String> {
+        let typename = match value {
+            DataValue::String(_) => "str",
+            DataValue::Int(_) => "int",
+            DataValue::Float(_) => "float",
+            DataValue::Bool(_) => "bool",
+            DataValue::List(_) => "list",
+            DataValue::Null => "null",
+        };
+        if let Some(types) = self.types.as_ref() {
+            if !types.iter().any(|x| x == typename) {
+                return Some(format!(
+                    "value has type {}, expected {}",
+                    typename,
+                    types.join(" or ")
+                ));
+            }
+        }
+        let number = match value {
+            DataValue::Int(x) => Some(*x as f64),
+            DataValue::Float(x) => Some(*x),
+            _ => None,
+        };
+        if let Some(number) = number {
+            if self.min.map_or(false, |min| number < min)
+                || self.max.map_or(false, |max| number > max)
+            {
+                return Some(format!("value {} is out of range", number));
+            }
+        }
+        if let Some(values) = self.values.as_ref() {
+            if !values.contains(value) {
+                return Some(format!("value {} is not one of the allowed values", value));
+            }
+        }
+        None
+    }
+}
+
 #[pyclass(name = "DataKeyIter")]
 struct PyDataKeyIter {
     pub(crate) handle: AnnotationDataSetHandle,
@@ -292,6 +591,41 @@ impl PyAnnotationDataIter {
             }
         }
     }
+
+    /// Consumes the remaining data and returns it as a plain list, decoupled from the live state of the store.
+    /// This is the recommended pattern if you want to modify the store while processing the data.
+    fn snapshot<'py>(&mut self, py: Python<'py>) -> &'py PyList {
+        let start = self.index;
+        let (handles, len): (Vec<AnnotationDataHandle>, usize) = self
+            .map(|dataset| {
+                Some((
+                    (start..dataset.data_len())
+                        .map(AnnotationDataHandle::new)
+                        .filter(|handle| {
+                            <AnnotationDataSet as StoreFor<AnnotationData>>::has(dataset, *handle)
+                        })
+                        .collect(),
+                    dataset.data_len(),
+                ))
+            })
+            .unwrap_or_default();
+        self.index = self.index.max(len);
+        let elements: Vec<Py<PyAnnotationData>> = handles
+            .into_iter()
+            .map(|handle| {
+                Py::new(
+                    py,
+                    PyAnnotationData {
+                        set: self.handle,
+                        handle,
+                        store: self.store.clone(),
+                    },
+                )
+                .expect("wrapping PyAnnotationData")
+            })
+            .collect();
+        PyList::new(py, elements)
+    }
 }
 
 impl PyAnnotationDataIter {
@@ -1,37 +1,49 @@
 use pyo3::exceptions::{PyException, PyIndexError, PyKeyError, PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
 use pyo3::types::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ops::FnOnce;
 use std::sync::{Arc, RwLock};
 
-use crate::annotation::PyAnnotation;
-use crate::annotationdata::PyAnnotationDataBuilder;
+use crate::annotation::{
+    annotation_content, has_data, resolve_data, sorted_spans, AnnotationContent, PyAnnotation,
+    PyAnnotationBuilder,
+};
+use crate::annotationdata::{
+    datavalue_into_py, py_into_databuilder, py_into_datavalue, PyAnnotationDataBuilder, PyDataKey,
+    TypedValue,
+};
 use crate::annotationdataset::PyAnnotationDataSet;
 use crate::error::PyStamError;
-use crate::resources::{PyTextResource, PyTextSelection};
-use crate::selector::PySelector;
+use crate::resources::{compile_regex, PyTextResource, PyTextSelection};
+use crate::selector::{py_into_selector, PySelector};
 use stam::*;
 
 #[pyclass(dict, name = "AnnotationStore")]
 /// The AnnotationStore
 pub struct PyAnnotationStore {
     store: Arc<RwLock<AnnotationStore>>,
+    /// Cached result of `estimated_tokens()`: the pattern, the number of resources it was computed for, and the estimate
+    token_estimate: Option<(String, usize, usize)>,
 }
 
 #[pymethods]
 impl PyAnnotationStore {
     #[new]
     #[args(kwargs = "**")]
-    fn new(kwargs: Option<&PyDict>) -> PyResult<Self> {
+    /// Loading from `file` or `string` releases the GIL while parsing, so other Python threads can run in the meantime.
+    fn new(kwargs: Option<&PyDict>, py: Python) -> PyResult<Self> {
         if let Some(kwargs) = kwargs {
             for (key, value) in kwargs {
                 if let Some(key) = key.extract().unwrap() {
                     match key {
                         "file" => {
                             if let Ok(Some(value)) = value.extract() {
-                                return match AnnotationStore::from_file(value) {
+                                return match py.allow_threads(|| AnnotationStore::from_file(value))
+                                {
                                     Ok(store) => Ok(PyAnnotationStore {
                                         store: Arc::new(RwLock::new(store)),
+                                        token_estimate: None,
                                     }),
                                     Err(err) => Err(PyStamError::new_err(format!("{}", err))),
                                 };
@@ -39,9 +51,10 @@ impl PyAnnotationStore {
                         }
                         "string" => {
                             if let Ok(Some(value)) = value.extract() {
-                                return match AnnotationStore::from_str(value) {
+                                return match py.allow_threads(|| AnnotationStore::from_str(value)) {
                                     Ok(store) => Ok(PyAnnotationStore {
                                         store: Arc::new(RwLock::new(store)),
+                                        token_estimate: None,
                                     }),
                                     Err(err) => Err(PyStamError::new_err(format!("{}", err))),
                                 };
@@ -53,6 +66,7 @@ impl PyAnnotationStore {
                                     store: Arc::new(RwLock::new(
                                         AnnotationStore::default().with_id(value),
                                     )),
+                                    token_estimate: None,
                                 });
                             }
                         }
@@ -63,9 +77,109 @@ impl PyAnnotationStore {
         }
         Ok(PyAnnotationStore {
             store: Arc::new(RwLock::new(AnnotationStore::default())),
+            token_estimate: None,
         })
     }
 
+    /// Constructs a complete annotation store from Python data structures in one call.
+    /// Resources are added first, then annotation sets, then annotations (in the order given), so annotations can refer to
+    /// anything added before them.
+    ///
+    /// * `resources` is a list of dictionaries with an `id` and `text`, or with a `filename` (and optionally an `id`).
+    /// * `annotationsets` is a list of dictionaries with an `id` and optionally `data`, a list of dictionaries with `key`,
+    ///   `value` and optionally `id`.
+    /// * `annotations` is a list of dictionaries with `target`, `data` and optionally `id`. The target is a dictionary with
+    ///   a `resource` ID and optionally `begin` and `end` offsets (without offsets, the resource as a whole is targeted),
+    ///   or with an `annotation` ID, or with an `annotationset` ID; a list of such dictionaries results in a MultiSelector.
+    ///   The data is a list of dictionaries with `set`, `key`, `value` and optionally `id`, or of public IDs of existing data.
+    ///
+    /// Raises an error describing the first item that could not be added, e.g. because of an unresolvable reference.
+    #[staticmethod]
+    #[pyo3(signature = (resources=None, annotationsets=None, annotations=None, id=None))]
+    fn build(
+        resources: Option<Vec<&PyDict>>,
+        annotationsets: Option<Vec<&PyDict>>,
+        annotations: Option<Vec<&PyDict>>,
+        id: Option<String>,
+        py: Python,
+    ) -> PyResult<Self> {
+        let mut store = PyAnnotationStore {
+            store: Arc::new(RwLock::new(match id {
+                Some(id) => AnnotationStore::default().with_id(id),
+                None => AnnotationStore::default(),
+            })),
+            token_estimate: None,
+        };
+        for (i, resource) in resources.unwrap_or_default().into_iter().enumerate() {
+            let context = format!("Resource #{}", i);
+            let filename: Option<&str> = resource
+                .get_item("filename")
+                .map(|x| x.extract())
+                .transpose()?;
+            let text: Option<String> =
+                resource.get_item("text").map(|x| x.extract()).transpose()?;
+            let id: Option<&str> = resource.get_item("id").map(|x| x.extract()).transpose()?;
+            store
+                .add_resource(filename, text, id, None, py)
+                .map_err(|err| PyStamError::new_err(format!("{}: {}", context, err)))?;
+        }
+        for (i, annotationset) in annotationsets.unwrap_or_default().into_iter().enumerate() {
+            let context = format!("Annotation set #{}", i);
+            let set = store
+                .add_annotationset(field(annotationset, "id", &context)?.extract()?)
+                .map_err(|err| PyStamError::new_err(format!("{}: {}", context, err)))?;
+            if let Some(data) = annotationset.get_item("data") {
+                for (j, data) in data.extract::<Vec<&PyDict>>()?.into_iter().enumerate() {
+                    let context = format!("{}, data #{}", context, j);
+                    let id: Option<&str> = data.get_item("id").map(|x| x.extract()).transpose()?;
+                    set.add_data(
+                        field(data, "key", &context)?.extract()?,
+                        field(data, "value", &context)?,
+                        id,
+                        None,
+                    )
+                    .map_err(|err| PyStamError::new_err(format!("{}: {}", context, err)))?;
+                }
+            }
+        }
+        for (i, annotation) in annotations.unwrap_or_default().into_iter().enumerate() {
+            let context = format!("Annotation #{}", i);
+            let selector = store
+                .map(|store| Ok(store_target(store, field(annotation, "target", &context)?)))?
+                .map_err(|err| PyStamError::new_err(format!("{}: {}", context, err)))?;
+            let mut data: Vec<&PyAny> = Vec::new();
+            if let Some(items) = annotation.get_item("data") {
+                for (j, item) in items.extract::<Vec<&PyAny>>()?.into_iter().enumerate() {
+                    if let Ok(item) = item.downcast::<PyDict>() {
+                        let context = format!("{}, data #{}", context, j);
+                        let builder = PyAnnotationDataBuilder::new(
+                            field(item, "set", &context)?.extract()?,
+                            field(item, "key", &context)?.extract()?,
+                            field(item, "value", &context)?,
+                            item.get_item("id").map(|x| x.extract()).transpose()?,
+                        )?;
+                        data.push(Py::new(py, builder)?.into_ref(py));
+                    } else {
+                        data.push(item);
+                    }
+                }
+            }
+            let id: Option<String> = annotation.get_item("id").map(|x| x.extract()).transpose()?;
+            store
+                .annotate(
+                    Py::new(py, PySelector { selector })?.into_ref(py),
+                    Some(data),
+                    id,
+                    None,
+                    None,
+                    false,
+                    py,
+                )
+                .map_err(|err| PyStamError::new_err(format!("{}: {}", context, err)))?;
+        }
+        Ok(store)
+    }
+
     #[getter]
     /// Returns the public ID (by value, aka a copy)
     fn id(&self) -> PyResult<Option<String>> {
@@ -73,13 +187,62 @@ impl PyAnnotationStore {
     }
 
     /// Saves the annotation store to file using STAM JSON
-    fn to_file(&self, filename: &str) -> PyResult<()> {
-        self.map(|store| store.to_file(filename))
+    /// If `canonical` is set, object keys are sorted and whitespace is normalized, so the output is stable and diffs well.
+    /// If `inline_resources` is set, the text of all resources is included in the output, rather than references to stand-off files.
+    /// If `indent` is set, the output is pretty-printed with that many spaces of indentation, or minified if it is 0
+    /// (object keys are sorted in that case as well).
+    #[pyo3(signature = (filename, canonical=false, inline_resources=false, indent=None))]
+    fn to_file(
+        &self,
+        filename: &str,
+        canonical: bool,
+        inline_resources: bool,
+        indent: Option<usize>,
+    ) -> PyResult<()> {
+        if canonical || inline_resources || indent.is_some() {
+            let json = self.to_string(canonical, inline_resources, indent)?;
+            std::fs::write(filename, json).map_err(|err| {
+                PyStamError::new_err(format!("Unable to write {}: {}", filename, err))
+            })
+        } else {
+            self.map(|store| store.to_file(filename))
+        }
     }
 
     /// Returns the annotation store to one big STAM JSON string
-    fn to_string(&self) -> PyResult<String> {
-        self.map(|store| store.to_string())
+    /// If `canonical` is set, object keys are sorted and whitespace is normalized, so the output is stable and diffs well.
+    /// If `inline_resources` is set, the text of all resources is included in the output, rather than references to stand-off files,
+    /// so the output is self-contained. This does not affect the formatting of the output.
+    /// If `indent` is set, the output is pretty-printed with that many spaces of indentation, or minified if it is 0
+    /// (object keys are sorted in that case as well).
+    #[pyo3(signature = (canonical=false, inline_resources=false, indent=None))]
+    fn to_string(
+        &self,
+        canonical: bool,
+        inline_resources: bool,
+        indent: Option<usize>,
+    ) -> PyResult<String> {
+        let mut json = self.map(|store| store.to_string())?;
+        if inline_resources {
+            let texts: HashMap<String, String> = self.map(|store| {
+                Ok((0..store.resources_len())
+                    .filter_map(|i| store.resource_by_index(i))
+                    .filter_map(|resource| {
+                        resource
+                            .id()
+                            .map(|id| (id.to_owned(), resource.text().to_owned()))
+                    })
+                    .collect())
+            })?;
+            json = inline_resource_texts(&json, &texts)?;
+        }
+        if let Some(indent) = indent {
+            indent_json(&json, indent)
+        } else if canonical {
+            canonical_json(&json)
+        } else {
+            Ok(json)
+        }
     }
 
     /// Returns an AnnotationDataSet by ID
@@ -115,22 +278,63 @@ impl PyAnnotationStore {
     }
 
     /// Create a new TextResource and adds it to the store
+    ///
+    /// If only a `filename` is given, the text is read from that file.
+    /// If both `text` and `filename` are given, the resource is created in memory with the given text (the file is not read)
+    /// and is associated with the filename, so it can be written there as a stand-off resource when the store is saved.
+    ///
+    /// If an `encoding` is specified (e.g. "latin-1"), the file is decoded from that encoding at load time
+    /// and normalized to NFC, as STAM always holds text as UTF-8.
+    #[pyo3(signature = (filename=None, text=None, id=None, encoding=None))]
     fn add_resource(
         &mut self,
         filename: Option<&str>,
         text: Option<String>,
         id: Option<&str>,
+        encoding: Option<&str>,
+        py: Python,
     ) -> PyResult<PyTextResource> {
         if id.is_none() && filename.is_none() {
             return Err(PyRuntimeError::new_err(
                 "Incomplete, set either id or filename",
             ));
         }
-        if filename.is_some() && text.is_some() {
-            return Err(PyRuntimeError::new_err(
-                "Set either filename or text keyword arguments, but not both",
-            ));
-        }
+        let text = match (text, filename) {
+            (Some(_), _) if encoding.is_some() => {
+                return Err(PyRuntimeError::new_err(
+                    "The encoding keyword argument can only be used when reading from filename, not with text",
+                ));
+            }
+            (Some(text), _) => Some(text),
+            (None, Some(filename)) => {
+                let bytes = std::fs::read(filename).map_err(|err| {
+                    PyStamError::new_err(format!("Unable to read {}: {}", filename, err))
+                })?;
+                if let Some(encoding) = encoding {
+                    let decoded = PyBytes::new(py, &bytes).call_method1("decode", (encoding,))?;
+                    Some(
+                        py.import("unicodedata")?
+                            .call_method1("normalize", ("NFC", decoded))?
+                            .extract::<String>()?,
+                    )
+                } else {
+                    Some(String::from_utf8(bytes).map_err(|err| {
+                        PyStamError::new_err(format!(
+                            "{} is not valid UTF-8 (set encoding?): {}",
+                            filename, err
+                        ))
+                    })?)
+                }
+            }
+            (None, None) => {
+                if encoding.is_some() {
+                    return Err(PyRuntimeError::new_err(
+                        "The encoding keyword argument can only be used with filename",
+                    ));
+                }
+                None
+            }
+        };
         let store_clone = self.store.clone(); //just a smart pointer clone, not the whole store
         self.map_mut(|store| {
             let mut resource = TextResource::new(
@@ -140,6 +344,9 @@ impl PyAnnotationStore {
             if let Some(text) = text {
                 resource = resource.with_string(text);
             }
+            if let Some(filename) = filename {
+                resource = resource.with_filename(filename);
+            }
             let handle = store.insert(resource)?;
             Ok(PyTextResource {
                 handle,
@@ -148,6 +355,59 @@ impl PyAnnotationStore {
         })
     }
 
+    /// Creates a new resource with the given `id` whose text is the concatenation of the texts of the given resources,
+    /// joined by `separator`. For each (non-empty) source resource, an annotation is added that maps the source text to its
+    /// location in the combined text: it has a DirectionalSelector with a TextSelector on the source and one on the
+    /// combined resource, and the `Transposition` key from the STAM transpose vocabulary as data. Returns the new resource.
+    /// Raises a ValueError if any of the resources belongs to another store.
+    #[pyo3(signature = (resources, id, separator="\n"))]
+    fn concat_resources(
+        &mut self,
+        resources: Vec<PyRef<PyTextResource>>,
+        id: String,
+        separator: &str,
+    ) -> PyResult<PyTextResource> {
+        for resource in resources.iter() {
+            self.check_same_store(&**resource)?;
+        }
+        let store_clone = self.store.clone();
+        self.map_mut(|store| {
+            let mut text = String::new();
+            let mut mappings: Vec<(TextResourceHandle, usize, usize)> = Vec::new();
+            let mut offset = 0;
+            for (i, resource) in resources.iter().enumerate() {
+                if i > 0 {
+                    text.push_str(separator);
+                    offset += separator.chars().count();
+                }
+                let source: &TextResource = store.get(resource.handle)?;
+                text.push_str(source.text());
+                mappings.push((resource.handle, offset, source.textlen()));
+                offset += source.textlen();
+            }
+            let handle = store.insert(TextResource::new(id).with_string(text))?;
+            for (source, begin, len) in mappings.into_iter().filter(|(_, _, len)| *len > 0) {
+                let mut databuilder = AnnotationDataBuilder::default();
+                databuilder.annotationset =
+                    AnyId::Id("https://w3id.org/stam/extensions/stam-transpose/".to_string());
+                databuilder.key = AnyId::Id("Transposition".to_string());
+                databuilder.value = DataValue::Null;
+                store.annotate(
+                    AnnotationBuilder::new()
+                        .with_selector(Selector::DirectionalSelector(vec![
+                            Selector::TextSelector(source, Offset::simple(0, len)),
+                            Selector::TextSelector(handle, Offset::simple(begin, begin + len)),
+                        ]))
+                        .with_data_builder(databuilder),
+                )?;
+            }
+            Ok(PyTextResource {
+                handle,
+                store: store_clone,
+            })
+        })
+    }
+
     /// Create a new AnnotationDataSet and adds it to the store
     fn add_annotationset(&mut self, id: String) -> PyResult<PyAnnotationDataSet> {
         let store_clone = self.store.clone();
@@ -162,19 +422,90 @@ impl PyAnnotationStore {
     }
 
     /// Adds an annotation. Returns an Annotation instance pointing to the added annotation.
+    /// The target is typically a Selector, but a TextSelection, an Annotation, or a (TextResource, Offset) tuple are accepted as well.
+    /// A list of targets results in a MultiSelector.
+    ///
+    /// The data is a list of AnnotationDataBuilder instances, existing AnnotationData instances, or public IDs of existing AnnotationData.
+    ///
+    /// Alternatively, an AnnotationBuilder may be passed instead of the target, in which case its ID, target and data are used.
+    /// Any further `data` is then added to that of the builder, and an explicit `id` takes precedence.
+    ///
+    /// If no `id` is given, one can be generated from the index the annotation will get in the store,
+    /// either by appending it to `id_prefix`, or by calling `id_formatter`, a callable that takes the index and returns the ID.
+    ///
+    /// If `dry_run` is set, the store is not changed. The ID, the target (including offsets relative to annotations) and the
+    /// annotation sets, keys and data that the data refers to are validated, raising an error where a real run would fail,
+    /// and a dictionary is returned describing the annotation that would be added: its `id`, `selector` and the
+    /// number of `data` items.
+    #[pyo3(signature = (target, data=None, id=None, id_prefix=None, id_formatter=None, dry_run=false))]
     fn annotate(
         &mut self,
-        target: PySelector,
-        data: Vec<PyRef<PyAnnotationDataBuilder>>,
+        target: &PyAny,
+        data: Option<Vec<&PyAny>>,
         id: Option<String>,
-    ) -> PyResult<PyAnnotation> {
+        id_prefix: Option<&str>,
+        id_formatter: Option<&PyAny>,
+        dry_run: bool,
+        py: Python,
+    ) -> PyResult<PyObject> {
+        let (selector, id, data) =
+            if let Ok(template) = target.extract::<PyRef<PyAnnotationBuilder>>() {
+                let mut templatedata: Vec<&PyAny> =
+                    template.data.iter().map(|item| item.as_ref(py)).collect();
+                templatedata.extend(data.unwrap_or_default());
+                (
+                    template.selector(py)?,
+                    id.or_else(|| template.id.clone()),
+                    templatedata,
+                )
+            } else {
+                (py_into_selector(target)?, id, data.unwrap_or_default())
+            };
+        let id = if id.is_none() && (id_prefix.is_some() || id_formatter.is_some()) {
+            let index = self.map(|store| Ok(store.annotations_len()))?;
+            if let Some(id_formatter) = id_formatter {
+                Some(id_formatter.call1((index,))?.extract::<String>()?)
+            } else {
+                id_prefix.map(|id_prefix| format!("{}{}", id_prefix, index))
+            }
+        } else {
+            id
+        };
+        let databuilders = self.map(|store| {
+            Ok(data
+                .iter()
+                .map(|item| py_into_databuilder(item, store))
+                .collect::<Vec<_>>())
+        })?;
+        let databuilders = databuilders.into_iter().collect::<PyResult<Vec<_>>>()?;
+        if dry_run {
+            self.map(|store| {
+                if let Some(id) = id.as_deref() {
+                    if store.resolve_annotation_id(id).is_ok() {
+                        return Err(StamError::OtherError(
+                            "An annotation with this ID already exists",
+                        ));
+                    }
+                }
+                validate_selector(store, &selector)?;
+                for databuilder in databuilders.iter() {
+                    validate_databuilder(store, databuilder)?;
+                }
+                Ok(())
+            })?;
+            let result = PyDict::new(py);
+            result.set_item("id", id)?;
+            result.set_item("selector", Py::new(py, PySelector { selector })?)?;
+            result.set_item("data", databuilders.len())?;
+            return Ok(result.into());
+        }
         let mut builder = AnnotationBuilder::new();
         if let Some(id) = id {
             builder = builder.with_id(id);
         }
-        builder = builder.with_selector(target.selector);
-        for databuilder in data.iter() {
-            builder = builder.with_data_builder(databuilder.builder.clone()); //MAYBE TODO: I don't like needing an extra clone here, but it can't move out of the PyRef
+        builder = builder.with_selector(selector);
+        for databuilder in databuilders {
+            builder = builder.with_data_builder(databuilder);
         }
         let store_clone = self.store.clone(); //just a smart pointer clone, not the whole store
         self.map_mut(|store| {
@@ -183,6 +514,193 @@ impl PyAnnotationStore {
                 store: store_clone,
             })
         })
+        .map(|annotation| annotation.into_py(py))
+    }
+
+    /// Adds annotations on a resource from a pandas DataFrame (or anything else with a compatible `to_dict("records")` method).
+    /// Each row must have the columns `begin` and `end` (offsets in unicode points), `key` and `value`, and a `set` column
+    /// unless the `annotationset` argument is given. Each row results in an annotation with a single data item, unless
+    /// `id_column` is set, in which case rows with the same value in that column are grouped into one annotation with
+    /// that ID, carrying the data of all these rows (the offsets of the first row are used).
+    /// Returns a tuple of the added annotations.
+    #[pyo3(signature = (df, resource, annotationset=None, id_column=None))]
+    fn add_annotations_from_dataframe<'py>(
+        &mut self,
+        df: &PyAny,
+        resource: PyRef<PyTextResource>,
+        annotationset: Option<&str>,
+        id_column: Option<&str>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let records: Vec<&PyDict> = df.call_method1("to_dict", ("records",))?.extract()?;
+        let mut builders: Vec<(Option<String>, Offset, Vec<AnnotationDataBuilder>)> = Vec::new();
+        let mut ids: HashMap<String, usize> = HashMap::new();
+        for (i, record) in records.iter().enumerate() {
+            let column = |name: &str| -> PyResult<&PyAny> {
+                record.get_item(name).ok_or_else(|| {
+                    PyValueError::new_err(format!("Row {} has no column {}", i, name))
+                })
+            };
+            let mut databuilder = AnnotationDataBuilder::default();
+            databuilder.annotationset = AnyId::Id(match record.get_item("set") {
+                Some(set) => set.extract()?,
+                None => annotationset
+                    .ok_or_else(|| {
+                        PyValueError::new_err(format!(
+                            "Row {} has no column set and no annotationset was given",
+                            i
+                        ))
+                    })?
+                    .to_string(),
+            });
+            databuilder.key = AnyId::Id(column("key")?.extract()?);
+            databuilder.value = py_into_datavalue(column("value")?)
+                .map_err(|err| PyStamError::new_err(format!("{}", err)))?;
+            let id: Option<String> = match id_column {
+                Some(id_column) => Some(column(id_column)?.str()?.to_string()),
+                None => None,
+            };
+            if let Some(index) = id.as_ref().and_then(|id| ids.get(id)) {
+                builders[*index].2.push(databuilder);
+                continue;
+            }
+            let offset = Offset::simple(column("begin")?.extract()?, column("end")?.extract()?);
+            if let Some(id) = id.as_ref() {
+                ids.insert(id.clone(), builders.len());
+            }
+            builders.push((id, offset, vec![databuilder]));
+        }
+        let resource_handle = resource.handle;
+        let handles = self.map_mut(|store| {
+            let mut handles = Vec::with_capacity(builders.len());
+            for (id, offset, databuilders) in builders {
+                let mut builder = AnnotationBuilder::new()
+                    .with_selector(Selector::TextSelector(resource_handle, offset));
+                if let Some(id) = id {
+                    builder = builder.with_id(id);
+                }
+                for databuilder in databuilders {
+                    builder = builder.with_data_builder(databuilder);
+                }
+                handles.push(store.annotate(builder)?);
+            }
+            Ok(handles)
+        })?;
+        let elements: Vec<Py<PyAnnotation>> = handles
+            .into_iter()
+            .map(|handle| {
+                Py::new(
+                    py,
+                    PyAnnotation {
+                        handle,
+                        store: self.store.clone(),
+                    },
+                )
+                .expect("wrapping PyAnnotation")
+            })
+            .collect();
+        Ok(PyTuple::new(py, elements))
+    }
+
+    /// Imports a W3C Web Annotation, passed as a JSON-LD string or a dictionary, and adds it as an annotation.
+    ///
+    /// The target must have a `source` that resolves to a resource in this store, either by its full IRI or by the last
+    /// component of its path. It must have a TextPositionSelector, or a FragmentSelector with a `char=begin,end` fragment (RFC 5147).
+    /// A target may also be a plain IRI with such a fragment.
+    /// All properties of the body (except `id` and `type`) are added as data, with the property name as key, in the annotation set `annotationset`.
+    #[pyo3(signature = (webannotation, annotationset="webannotation"))]
+    fn add_webannotation(
+        &mut self,
+        webannotation: &PyAny,
+        annotationset: &str,
+        py: Python,
+    ) -> PyResult<PyAnnotation> {
+        let json: String = if let Ok(json) = webannotation.extract() {
+            json
+        } else {
+            py.import("json")?
+                .call_method1("dumps", (webannotation,))?
+                .extract()?
+        };
+        let webannotation: serde_json::Value = serde_json::from_str(&json).map_err(|err| {
+            PyStamError::new_err(format!("Unable to parse web annotation: {}", err))
+        })?;
+        let (source, begin, end) = webannotation_target(&webannotation["target"])?;
+        let resource_handle = self.map(|store| {
+            store
+                .resolve_resource_id(&source)
+                .or_else(|err| match source.rsplit('/').next() {
+                    Some(id) if id != source => store.resolve_resource_id(id),
+                    _ => Err(err),
+                })
+        })?;
+        let mut builder = AnnotationBuilder::new().with_selector(Selector::TextSelector(
+            resource_handle,
+            Offset::simple(begin, end),
+        ));
+        if let Some(id) = webannotation["id"].as_str() {
+            builder = builder.with_id(id.to_string());
+        }
+        let bodies: Vec<&serde_json::Value> = match &webannotation["body"] {
+            serde_json::Value::Array(bodies) => bodies.iter().collect(),
+            serde_json::Value::Null => Vec::new(),
+            body => vec![body],
+        };
+        for body in bodies {
+            if let serde_json::Value::Object(body) = body {
+                for (key, value) in body.iter() {
+                    if key == "id" || key == "type" || key.starts_with('@') {
+                        continue;
+                    }
+                    let mut databuilder = AnnotationDataBuilder::default();
+                    databuilder.annotationset = AnyId::Id(annotationset.to_string());
+                    databuilder.key = AnyId::Id(key.to_string());
+                    databuilder.value = json_into_datavalue(value)?;
+                    builder = builder.with_data_builder(databuilder);
+                }
+            } else {
+                return Err(PyStamError::new_err(
+                    "Web annotation bodies must be objects",
+                ));
+            }
+        }
+        let store_clone = self.store.clone();
+        self.map_mut(|store| {
+            Ok(PyAnnotation {
+                handle: store.annotate(builder)?,
+                store: store_clone,
+            })
+        })
+    }
+
+    /// Tags a text selection: annotates it with `tag` as the value of the key `tag` in the annotation set `set`.
+    /// The annotation set and key are created if they do not exist yet. Returns the new annotation.
+    /// Use `TextSelection.tags()` to read the tags back.
+    #[pyo3(signature = (textselection, tag, set="tags"))]
+    fn tag(
+        &mut self,
+        textselection: PyRef<PyTextSelection>,
+        tag: &str,
+        set: &str,
+    ) -> PyResult<PyAnnotation> {
+        let mut databuilder = AnnotationDataBuilder::default();
+        databuilder.annotationset = AnyId::Id(set.to_string());
+        databuilder.key = AnyId::Id("tag".to_string());
+        databuilder.value = DataValue::String(tag.to_string());
+        let builder = AnnotationBuilder::new()
+            .with_selector(Selector::TextSelector(
+                textselection.resource_handle,
+                Offset::simple(
+                    textselection.textselection.begin(),
+                    textselection.textselection.end(),
+                ),
+            ))
+            .with_data_builder(databuilder);
+        self.map_mut(|store| store.annotate(builder))
+            .map(|handle| PyAnnotation {
+                handle,
+                store: self.store.clone(),
+            })
     }
 
     /// Returns a generator over all annotations in this store
@@ -214,16 +732,532 @@ impl PyAnnotationStore {
         self.map(|store| Ok(store.annotations_len()))
     }
 
+    /// Returns a list of the distinct (AnnotationDataSet, DataKey) pairs that are actually used in the data of at least one
+    /// annotation, in order of first use. Unlike the keys declared in the annotation sets, this reflects the effective schema.
+    fn used_keys<'py>(&self, py: Python<'py>) -> PyResult<&'py PyList> {
+        let keys = self.map(|store| {
+            let mut keys: Vec<(AnnotationDataSetHandle, DataKeyHandle)> = Vec::new();
+            for annotation in
+                (0..store.annotations_len()).filter_map(|i| store.annotation_by_index(i))
+            {
+                for (set, data) in resolve_data(store, annotation) {
+                    if !keys.contains(&(set, data.key())) {
+                        keys.push((set, data.key()));
+                    }
+                }
+            }
+            Ok(keys)
+        })?;
+        let result = PyList::empty(py);
+        for (set, handle) in keys {
+            result.append((
+                Py::new(
+                    py,
+                    PyAnnotationDataSet {
+                        handle: set,
+                        store: self.store.clone(),
+                    },
+                )?,
+                Py::new(
+                    py,
+                    PyDataKey {
+                        set,
+                        handle,
+                        store: self.store.clone(),
+                    },
+                )?,
+            ))?;
+        }
+        Ok(result)
+    }
+
+    /// Returns a tuple of all annotations that carry no data at all. Such bare annotations are usually mistakes.
+    fn annotations_without_data<'py>(&self, py: Python<'py>) -> PyResult<&'py PyTuple> {
+        let elements: Vec<Py<PyAnnotation>> = self
+            .map(|store| {
+                Ok((0..store.annotations_len())
+                    .filter_map(|i| store.annotation_by_index(i))
+                    .filter(|annotation| annotation.data_by_index(0).is_none())
+                    .map(|annotation| annotation.handle().expect("annotation must have a handle"))
+                    .collect::<Vec<_>>())
+            })?
+            .into_iter()
+            .map(|handle| {
+                Py::new(
+                    py,
+                    PyAnnotation {
+                        handle,
+                        store: self.store.clone(),
+                    },
+                )
+                .expect("wrapping PyAnnotation")
+            })
+            .collect();
+        Ok(PyTuple::new(py, elements))
+    }
+
+    /// Merges the text spans of annotations that have the same data for `key` (in annotation set `set`, or in any set if
+    /// not specified): for each value, overlapping and adjacent spans are coalesced and a new annotation with that same
+    /// data is added on each span that was formed from more than one distinct annotation, unless one of those annotations
+    /// already covers exactly that span. This is how, for instance, BIO-tagged tokens
+    /// are turned into entity spans. Spans are also merged if the gap between them is at most `max_gap` characters, or,
+    /// if `allow_whitespace` is set, if there is only whitespace in between.
+    /// The original annotations are left in place. Returns a tuple of the new annotations.
+    #[pyo3(signature = (key, set=None, max_gap=0, allow_whitespace=false, id_prefix=None))]
+    fn merge_overlapping<'py>(
+        &mut self,
+        key: &str,
+        set: Option<&str>,
+        max_gap: usize,
+        allow_whitespace: bool,
+        id_prefix: Option<&str>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let builders = self.map(|store| {
+            // text spans per data and resource
+            let mut groups: HashMap<
+                (
+                    AnnotationDataSetHandle,
+                    AnnotationDataHandle,
+                    TextResourceHandle,
+                ),
+                Vec<(usize, usize, AnnotationHandle)>,
+            > = HashMap::new();
+            // all text spans of each annotation involved
+            let mut annotation_spans: HashMap<
+                AnnotationHandle,
+                Vec<(TextResourceHandle, usize, usize)>,
+            > = HashMap::new();
+            for annotation in
+                (0..store.annotations_len()).filter_map(|i| store.annotation_by_index(i))
+            {
+                let handle = annotation.handle().expect("annotation must have a handle");
+                let spans = sorted_spans(store, annotation);
+                for (sethandle, datahandle) in (0..).map_while(|i| annotation.data_by_index(i)) {
+                    let annotationset: &AnnotationDataSet = store.get(*sethandle)?;
+                    if set.map_or(false, |set| annotationset.id() != Some(set)) {
+                        continue;
+                    }
+                    let data: &AnnotationData = annotationset.get(*datahandle)?;
+                    let datakey: &DataKey = annotationset.get(data.key())?;
+                    if datakey.id() != Some(key) {
+                        continue;
+                    }
+                    for (resource_handle, begin, end) in spans.iter().copied() {
+                        groups
+                            .entry((*sethandle, *datahandle, resource_handle))
+                            .or_default()
+                            .push((begin, end, handle));
+                    }
+                    annotation_spans
+                        .entry(handle)
+                        .or_insert_with(|| spans.clone());
+                }
+            }
+            let mut groups: Vec<_> = groups.into_iter().collect();
+            groups.sort_unstable_by_key(|((_, _, resource_handle), spans)| {
+                (
+                    *resource_handle,
+                    spans.iter().map(|(begin, end, _)| (*begin, *end)).min(),
+                )
+            });
+            let mut builders = Vec::new();
+            for ((sethandle, datahandle, resource_handle), mut spans) in groups {
+                let resource: &TextResource = store.get(resource_handle)?;
+                spans.sort_unstable();
+                let mut merged: Vec<(usize, usize, Vec<AnnotationHandle>)> = Vec::new();
+                for (begin, end, handle) in spans {
+                    if let Some(last) = merged.last_mut() {
+                        let mergeable = begin <= last.1
+                            || begin - last.1 <= max_gap
+                            || (allow_whitespace
+                                && resource
+                                    .text_slice(&Offset::simple(last.1, begin))?
+                                    .chars()
+                                    .all(char::is_whitespace));
+                        if mergeable {
+                            last.1 = last.1.max(end);
+                            if !last.2.contains(&handle) {
+                                last.2.push(handle);
+                            }
+                            continue;
+                        }
+                    }
+                    //begin, end and the distinct annotations merged into it
+                    merged.push((begin, end, vec![handle]));
+                }
+                for (begin, end, _) in merged.into_iter().filter(|(begin, end, handles)| {
+                    //only spans formed from multiple annotations, and not already covered by one of them exactly
+                    handles.len() > 1
+                        && !handles.iter().any(|handle| {
+                            annotation_spans.get(handle).map_or(false, |spans| {
+                                spans.as_slice() == [(resource_handle, *begin, *end)]
+                            })
+                        })
+                }) {
+                    let mut databuilder = AnnotationDataBuilder::default();
+                    databuilder.id = AnyId::Handle(datahandle);
+                    databuilder.annotationset = AnyId::Handle(sethandle);
+                    builders.push(
+                        AnnotationBuilder::new()
+                            .with_selector(Selector::TextSelector(
+                                resource_handle,
+                                Offset::simple(begin, end),
+                            ))
+                            .with_data_builder(databuilder),
+                    );
+                }
+            }
+            Ok(builders)
+        })?;
+        let handles = self.map_mut(|store| {
+            let index = store.annotations_len();
+            builders
+                .into_iter()
+                .enumerate()
+                .map(|(i, mut builder)| {
+                    if let Some(id_prefix) = id_prefix {
+                        builder = builder.with_id(format!("{}{}", id_prefix, index + i));
+                    }
+                    store.annotate(builder)
+                })
+                .collect::<Result<Vec<_>, StamError>>()
+        })?;
+        let elements: Vec<Py<PyAnnotation>> = handles
+            .into_iter()
+            .map(|handle| {
+                Py::new(
+                    py,
+                    PyAnnotation {
+                        handle,
+                        store: self.store.clone(),
+                    },
+                )
+                .expect("wrapping PyAnnotation")
+            })
+            .collect();
+        Ok(PyTuple::new(py, elements))
+    }
+
+    /// Returns a tuple of all annotations whose target references more than one distinct resource, either via their
+    /// text or via the resource as a whole. If a `key` is provided, only annotations with data for that key are returned,
+    /// and if a `value` is provided as well, the data must also have that value.
+    #[pyo3(signature = (key=None, value=None))]
+    fn cross_resource_annotations<'py>(
+        &self,
+        key: Option<PyRef<PyDataKey>>,
+        value: Option<&PyAny>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let value = value
+            .map(py_into_datavalue)
+            .transpose()
+            .map_err(|err| PyStamError::new_err(format!("{}", err)))?;
+        let elements: Vec<Py<PyAnnotation>> = self
+            .map(|store| {
+                Ok((0..store.annotations_len())
+                    .filter_map(|i| store.annotation_by_index(i))
+                    .filter(|annotation| {
+                        let mut resources: Vec<TextResourceHandle> = store
+                            .textselections_by_annotation(annotation)
+                            .map(|(reshandle, _)| reshandle)
+                            .collect();
+                        selector_resources(annotation.target(), &mut resources);
+                        resources.sort_unstable();
+                        resources.dedup();
+                        resources.len() > 1
+                            && has_data(store, annotation, key.as_deref(), value.as_ref())
+                    })
+                    .map(|annotation| annotation.handle().expect("annotation must have a handle"))
+                    .collect::<Vec<_>>())
+            })?
+            .into_iter()
+            .map(|handle| {
+                Py::new(
+                    py,
+                    PyAnnotation {
+                        handle,
+                        store: self.store.clone(),
+                    },
+                )
+                .expect("wrapping PyAnnotation")
+            })
+            .collect();
+        Ok(PyTuple::new(py, elements))
+    }
+
     /// Returns the number of resources in the store (not substracting deletions)
     fn resources_len(&self) -> PyResult<usize> {
         self.map(|store| Ok(store.resources_len()))
     }
 
+    /// Returns the total length of the text of all resources in the store, in unicode points
+    fn total_textlen(&self) -> PyResult<usize> {
+        self.map(|store| {
+            Ok((0..store.resources_len())
+                .filter_map(|i| store.resource_by_index(i))
+                .map(|resource| resource.textlen())
+                .sum())
+        })
+    }
+
+    /// Returns an estimate of the number of tokens in the text of all resources in the store.
+    /// By default, runs of word characters and individual punctuation characters are counted as tokens,
+    /// a custom regular expression matching a single token can be passed as `pattern`.
+    /// The result is cached until resources are added (the text of existing resources never changes).
+    #[pyo3(signature = (pattern=None))]
+    fn estimated_tokens(&mut self, pattern: Option<&str>) -> PyResult<usize> {
+        let pattern = pattern.unwrap_or(r"\w+|[^\w\s]");
+        let resources_len = self.map(|store| Ok(store.resources_len()))?;
+        if let Some((cached_pattern, cached_len, estimate)) = &self.token_estimate {
+            if cached_pattern == pattern && *cached_len == resources_len {
+                return Ok(*estimate);
+            }
+        }
+        let regex = compile_regex(pattern)?;
+        let estimate = self.map(|store| {
+            Ok((0..store.resources_len())
+                .filter_map(|i| store.resource_by_index(i))
+                .map(|resource| regex.find_iter(resource.text()).count())
+                .sum())
+        })?;
+        self.token_estimate = Some((pattern.to_owned(), resources_len, estimate));
+        Ok(estimate)
+    }
+
     /// Returns the number of annotation data sets in the store (not substracting deletions)
     fn annotationsets_len(&self) -> PyResult<usize> {
         self.map(|store| Ok(store.annotationsets_len()))
     }
 
+    /// Returns a set of the public IDs of all annotations in the store
+    /// Annotations without a public ID are skipped, unless `include_none` is set, in which case `None` is added to the set.
+    #[pyo3(signature = (include_none=false))]
+    fn annotation_ids(&self, include_none: bool) -> PyResult<HashSet<Option<String>>> {
+        self.map(|store| {
+            Ok((0..store.annotations_len())
+                .filter_map(|i| store.annotation_by_index(i))
+                .map(|annotation| annotation.id().map(|x| x.to_owned()))
+                .filter(|id| include_none || id.is_some())
+                .collect())
+        })
+    }
+
+    /// Returns a set of the public IDs of all resources in the store
+    /// Resources without a public ID are skipped, unless `include_none` is set, in which case `None` is added to the set.
+    #[pyo3(signature = (include_none=false))]
+    fn resource_ids(&self, include_none: bool) -> PyResult<HashSet<Option<String>>> {
+        self.map(|store| {
+            Ok((0..store.resources_len())
+                .filter_map(|i| store.resource_by_index(i))
+                .map(|resource| resource.id().map(|x| x.to_owned()))
+                .filter(|id| include_none || id.is_some())
+                .collect())
+        })
+    }
+
+    /// Returns a set of the public IDs of all annotation data sets in the store
+    /// Sets without a public ID are skipped, unless `include_none` is set, in which case `None` is added to the set.
+    #[pyo3(signature = (include_none=false))]
+    fn annotationset_ids(&self, include_none: bool) -> PyResult<HashSet<Option<String>>> {
+        self.map(|store| {
+            Ok((0..store.annotationsets_len())
+                .filter_map(|i| store.annotationset_by_index(i))
+                .map(|annotationset| annotationset.id().map(|x| x.to_owned()))
+                .filter(|id| include_none || id.is_some())
+                .collect())
+        })
+    }
+
+    /// Computes span-level agreement between two sequences of annotations, where `a` is taken as the reference and `b` as the candidate.
+    /// Annotations match if they cover exactly the same text spans; annotations without text (e.g. on a resource as a whole)
+    /// never match. All annotations, and the key, must belong to this store.
+    /// Returns a dictionary with `precision`, `recall` and `f1`, along with the raw counts (`true_positives`, `a`, `b`).
+    /// If a DataKey is provided as `key`, the values for that key are compared (along with their type) for the matching annotations
+    /// and `label_accuracy` and Cohen's `kappa` are added.
+    #[pyo3(signature = (a, b, key=None))]
+    fn agreement<'py>(
+        &self,
+        a: Vec<PyRef<PyAnnotation>>,
+        b: Vec<PyRef<PyAnnotation>>,
+        key: Option<PyRef<PyDataKey>>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyDict> {
+        for annotation in a.iter().chain(b.iter()) {
+            self.check_same_store(&**annotation)?;
+        }
+        if let Some(key) = key.as_ref() {
+            self.check_same_store(&**key)?;
+        }
+        let (true_positives, labelpairs) = self.map(|store| {
+            let label = |annotation: &Annotation| -> Option<TypedValue> {
+                key.as_ref().and_then(|key| {
+                    resolve_data(store, annotation)
+                        .find(|(set, data)| *set == key.set && data.key() == key.handle)
+                        .map(|(_, data)| TypedValue(data.value().clone()))
+                })
+            };
+            let mut candidates: HashMap<Vec<(TextResourceHandle, usize, usize)>, Vec<&Annotation>> =
+                HashMap::new();
+            for annotation in b.iter() {
+                let annotation: &Annotation = store.get(annotation.handle)?;
+                let spans = sorted_spans(store, annotation);
+                if !spans.is_empty() {
+                    candidates.entry(spans).or_default().push(annotation);
+                }
+            }
+            let mut true_positives = 0;
+            let mut labelpairs: Vec<(Option<TypedValue>, Option<TypedValue>)> = Vec::new();
+            for annotation in a.iter() {
+                let annotation: &Annotation = store.get(annotation.handle)?;
+                if let Some(candidate) = candidates
+                    .get_mut(&sorted_spans(store, annotation))
+                    .and_then(|candidates| candidates.pop())
+                {
+                    true_positives += 1;
+                    if key.is_some() {
+                        labelpairs.push((label(annotation), label(candidate)));
+                    }
+                }
+            }
+            Ok((true_positives, labelpairs))
+        })?;
+        let precision = if b.is_empty() {
+            0.0
+        } else {
+            true_positives as f64 / b.len() as f64
+        };
+        let recall = if a.is_empty() {
+            0.0
+        } else {
+            true_positives as f64 / a.len() as f64
+        };
+        let f1 = if precision + recall == 0.0 {
+            0.0
+        } else {
+            2.0 * precision * recall / (precision + recall)
+        };
+        let result = PyDict::new(py);
+        result.set_item("precision", precision)?;
+        result.set_item("recall", recall)?;
+        result.set_item("f1", f1)?;
+        result.set_item("true_positives", true_positives)?;
+        result.set_item("a", a.len())?;
+        result.set_item("b", b.len())?;
+        if key.is_some() {
+            let total = labelpairs.len() as f64;
+            let agreed = labelpairs.iter().filter(|(x, y)| x == y).count() as f64;
+            let mut counts: HashMap<&Option<TypedValue>, (usize, usize)> = HashMap::new();
+            for (x, y) in labelpairs.iter() {
+                counts.entry(x).or_default().0 += 1;
+                counts.entry(y).or_default().1 += 1;
+            }
+            let observed = if total > 0.0 { agreed / total } else { 0.0 };
+            let expected: f64 = if total > 0.0 {
+                counts
+                    .values()
+                    .map(|(x, y)| (*x as f64 / total) * (*y as f64 / total))
+                    .sum()
+            } else {
+                0.0
+            };
+            let kappa = if expected >= 1.0 {
+                1.0
+            } else {
+                (observed - expected) / (1.0 - expected)
+            };
+            result.set_item("label_accuracy", observed)?;
+            result.set_item("kappa", kappa)?;
+        }
+        Ok(result)
+    }
+
+    /// Compares this store (the old state) with another store (the new state) and returns what changed, as a dictionary
+    /// with the keys `annotations`, `resources` and `annotationsets`. Each holds a dictionary with `added`, `removed` and `changed` lists.
+    ///
+    /// Resources and annotation sets are compared by public ID; a resource changed if its text differs, an annotation set if
+    /// its keys or data differ. Annotations with a public ID are compared by ID; they changed if their target spans or data differ.
+    /// Annotations without a public ID are matched on their spans and data.
+    /// Values are compared along with their type, so changing `"1"` into `1` counts as a change.
+    /// Annotations are reported as dictionaries with `id`, `spans` (a list of (resource ID, begin, end) tuples) and `data` (a list of
+    /// (set ID, key ID, value) tuples); changed annotations as a dictionary with `id`, `old` and `new`.
+    fn diff<'py>(&self, other: &PyAnnotationStore, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let (old_annotations, old_resources, old_sets) =
+            self.map(|store| Ok(store_contents(store)))?;
+        let (new_annotations, new_resources, new_sets) =
+            other.map(|store| Ok(store_contents(store)))?;
+
+        let annotation_dict =
+            |id: &Option<String>, content: &AnnotationContent| -> PyResult<&'py PyDict> {
+                let dict = PyDict::new(py);
+                dict.set_item("id", id)?;
+                dict.set_item("spans", &content.0)?;
+                dict.set_item("data", &content.1)?;
+                Ok(dict)
+            };
+        let added = PyList::empty(py);
+        let removed = PyList::empty(py);
+        let changed = PyList::empty(py);
+        let mut anonymous: HashMap<&AnnotationContent, usize> = HashMap::new();
+        for (id, content) in new_annotations.iter() {
+            if id.is_none() {
+                *anonymous.entry(content).or_default() += 1;
+            }
+        }
+        let new_by_id: HashMap<&String, &AnnotationContent> = new_annotations
+            .iter()
+            .filter_map(|(id, content)| id.as_ref().map(|id| (id, content)))
+            .collect();
+        let mut seen: HashSet<&String> = HashSet::new();
+        for (id, content) in old_annotations.iter() {
+            match id {
+                Some(id) => {
+                    seen.insert(id);
+                    match new_by_id.get(id) {
+                        Some(new_content) if *new_content == content => {}
+                        Some(new_content) => {
+                            let dict = PyDict::new(py);
+                            dict.set_item("id", id)?;
+                            dict.set_item("old", annotation_dict(&None, content)?)?;
+                            dict.set_item("new", annotation_dict(&None, new_content)?)?;
+                            changed.append(dict)?;
+                        }
+                        None => removed.append(annotation_dict(&Some(id.clone()), content)?)?,
+                    }
+                }
+                None => match anonymous.get_mut(content) {
+                    Some(count) if *count > 0 => *count -= 1,
+                    _ => removed.append(annotation_dict(&None, content)?)?,
+                },
+            }
+        }
+        for (id, content) in new_annotations.iter() {
+            match id {
+                Some(id) if !seen.contains(id) => {
+                    added.append(annotation_dict(&Some(id.clone()), content)?)?
+                }
+                None => {
+                    if let Some(count) = anonymous.get_mut(content) {
+                        if *count > 0 {
+                            *count -= 1;
+                            added.append(annotation_dict(&None, content)?)?
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let result = PyDict::new(py);
+        let annotations = PyDict::new(py);
+        annotations.set_item("added", added)?;
+        annotations.set_item("removed", removed)?;
+        annotations.set_item("changed", changed)?;
+        result.set_item("annotations", annotations)?;
+        result.set_item("resources", diff_by_id(&old_resources, &new_resources, py)?)?;
+        result.set_item("annotationsets", diff_by_id(&old_sets, &new_sets, py)?)?;
+        Ok(result)
+    }
+
     /// Applies a selector to the annotation store and returns the target(s)
     /// May return a multitude of types depending on the selector, returns
     /// a list if multiple targets were found (internally consumes an iterator).
@@ -485,6 +1519,449 @@ impl PyAnnotationStore {
     }
 }
 
+/// Gets a required field from a dictionary as used by `AnnotationStore.build()`
+fn field<'py>(item: &'py PyDict, name: &str, context: &str) -> PyResult<&'py PyAny> {
+    item.get_item(name)
+        .ok_or_else(|| PyValueError::new_err(format!("{} has no {}", context, name)))
+}
+
+/// Resolves a target specification as used by `AnnotationStore.build()` to a selector
+fn store_target(store: &AnnotationStore, target: &PyAny) -> PyResult<Selector> {
+    if let Ok(targets) = target.downcast::<PyList>() {
+        if targets.is_empty() {
+            return Err(PyValueError::new_err("Target list may not be empty"));
+        }
+        return Ok(Selector::MultiSelector(
+            targets
+                .iter()
+                .map(|target| store_target(store, target))
+                .collect::<PyResult<Vec<_>>>()?,
+        ));
+    }
+    let target: &PyDict = target.downcast().map_err(|_| {
+        PyValueError::new_err("Target must be a dictionary or a list of dictionaries")
+    })?;
+    let stamerror = |err: StamError| PyStamError::new_err(format!("{}", err));
+    if let Some(resource) = target.get_item("resource") {
+        let handle = store
+            .resolve_resource_id(resource.extract()?)
+            .map_err(stamerror)?;
+        match (target.get_item("begin"), target.get_item("end")) {
+            (Some(begin), Some(end)) => Ok(Selector::TextSelector(
+                handle,
+                Offset::simple(begin.extract()?, end.extract()?),
+            )),
+            (None, None) => Ok(Selector::ResourceSelector(handle)),
+            _ => Err(PyValueError::new_err(
+                "Target must have both begin and end, or neither",
+            )),
+        }
+    } else if let Some(annotation) = target.get_item("annotation") {
+        Ok(Selector::AnnotationSelector(
+            store
+                .resolve_annotation_id(annotation.extract()?)
+                .map_err(stamerror)?,
+            None,
+        ))
+    } else if let Some(annotationset) = target.get_item("annotationset") {
+        Ok(Selector::DataSetSelector(
+            store
+                .resolve_dataset_id(annotationset.extract()?)
+                .map_err(stamerror)?,
+        ))
+    } else {
+        Err(PyValueError::new_err(
+            "Target must have a resource, annotation or annotationset",
+        ))
+    }
+}
+
+/// Collects the resources a selector targets as a whole (via ResourceSelectors)
+fn selector_resources(selector: &Selector, resources: &mut Vec<TextResourceHandle>) {
+    match selector {
+        Selector::ResourceSelector(handle) => resources.push(*handle),
+        Selector::MultiSelector(v)
+        | Selector::CompositeSelector(v)
+        | Selector::DirectionalSelector(v) => {
+            for subselector in v.iter() {
+                selector_resources(subselector, resources);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Checks that everything a selector refers to exists and that its offsets are valid, without changing anything
+fn validate_selector(store: &AnnotationStore, selector: &Selector) -> Result<(), StamError> {
+    match selector {
+        Selector::ResourceSelector(handle) => {
+            let _: &TextResource = store.get(*handle)?;
+        }
+        Selector::DataSetSelector(handle) => {
+            let _: &AnnotationDataSet = store.get(*handle)?;
+        }
+        Selector::TextSelector(handle, offset) => {
+            let resource: &TextResource = store.get(*handle)?;
+            resource.textselection(offset)?;
+        }
+        Selector::AnnotationSelector(handle, None) => {
+            let _: &Annotation = store.get(*handle)?;
+        }
+        Selector::AnnotationSelector(handle, Some(offset)) => {
+            //the offset is relative to the (single) text selection of the target annotation
+            let annotation: &Annotation = store.get(*handle)?;
+            let mut textselections = store.textselections_by_annotation(annotation);
+            let (begin, end) = match (textselections.next(), textselections.next()) {
+                (Some((_, textselection)), None) => {
+                    (textselection.begin() as isize, textselection.end() as isize)
+                }
+                _ => {
+                    return Err(StamError::OtherError(
+                        "An AnnotationSelector with an offset requires a target annotation with a single text selection",
+                    ))
+                }
+            };
+            let resolve = |cursor: &Cursor| match cursor {
+                Cursor::BeginAligned(v) => begin + *v as isize,
+                Cursor::EndAligned(v) => end + *v,
+            };
+            let (relbegin, relend) = (resolve(&offset.begin), resolve(&offset.end));
+            if relbegin < begin || relend > end || relbegin > relend {
+                return Err(StamError::OtherError(
+                    "The offset of the AnnotationSelector is out of bounds of the target annotation's text",
+                ));
+            }
+        }
+        Selector::MultiSelector(v)
+        | Selector::CompositeSelector(v)
+        | Selector::DirectionalSelector(v) => {
+            for subselector in v.iter() {
+                validate_selector(store, subselector)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Checks that the annotation set, key and data a data builder refers to can be resolved, without changing anything.
+/// Sets and keys referred to by ID may be missing, they are created when annotating, but then the builder must provide
+/// a key and can not refer to existing data.
+fn validate_databuilder(
+    store: &AnnotationStore,
+    databuilder: &AnnotationDataBuilder,
+) -> Result<(), StamError> {
+    let annotationset = store.annotationset(&databuilder.annotationset);
+    if annotationset.is_none() {
+        if let AnyId::Handle(_) = databuilder.annotationset {
+            return Err(StamError::OtherError(
+                "The annotation set referenced by the data does not exist",
+            ));
+        }
+    }
+    let data_exists = match (&databuilder.id, annotationset) {
+        (AnyId::Handle(_), None) => {
+            return Err(StamError::OtherError(
+                "The annotation data referenced does not exist",
+            ))
+        }
+        (AnyId::Handle(_), Some(annotationset)) => {
+            if annotationset.annotationdata(&databuilder.id).is_none() {
+                return Err(StamError::OtherError(
+                    "The annotation data referenced does not exist",
+                ));
+            }
+            true
+        }
+        (AnyId::Id(_), Some(annotationset)) => {
+            annotationset.annotationdata(&databuilder.id).is_some()
+        }
+        _ => false,
+    };
+    if !data_exists {
+        match (&databuilder.key, annotationset) {
+            (AnyId::Handle(_), Some(annotationset)) => {
+                if annotationset.key(&databuilder.key).is_none() {
+                    return Err(StamError::OtherError(
+                        "The data key referenced does not exist",
+                    ));
+                }
+            }
+            (AnyId::Handle(_), None) => {
+                return Err(StamError::OtherError(
+                    "The data key referenced does not exist",
+                ))
+            }
+            (AnyId::Id(_), _) => {}
+            _ => {
+                return Err(StamError::OtherError(
+                    "The annotation data does not exist and no key was provided to create it",
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Extracts the contents of a store for comparison with another: all annotations (by public ID) with their content,
+/// and the resources and annotation sets by public ID with a comparable representation of their contents
+#[allow(clippy::type_complexity)]
+fn store_contents(
+    store: &AnnotationStore,
+) -> (
+    Vec<(Option<String>, AnnotationContent)>,
+    HashMap<String, String>,
+    HashMap<String, Vec<(Option<String>, Option<TypedValue>)>>,
+) {
+    let annotations = (0..store.annotations_len())
+        .filter_map(|i| store.annotation_by_index(i))
+        .map(|annotation| {
+            (
+                annotation.id().map(|x| x.to_owned()),
+                annotation_content(store, annotation),
+            )
+        })
+        .collect();
+    let resources = (0..store.resources_len())
+        .filter_map(|i| store.resource_by_index(i))
+        .filter_map(|resource| {
+            resource
+                .id()
+                .map(|id| (id.to_owned(), resource.text().to_owned()))
+        })
+        .collect();
+    let annotationsets = (0..store.annotationsets_len())
+        .filter_map(|i| store.annotationset_by_index(i))
+        .filter_map(|annotationset| {
+            annotationset.id().map(|id| {
+                let mut contents: Vec<(Option<String>, Option<TypedValue>)> = (0..annotationset
+                    .keys_len())
+                    .filter_map(|i| annotationset.key(&DataKeyHandle::new(i).into()))
+                    .map(|key| (key.id().map(|x| x.to_owned()), None))
+                    .chain(
+                        (0..annotationset.data_len())
+                            .filter_map(|i| {
+                                annotationset.annotationdata(&AnnotationDataHandle::new(i).into())
+                            })
+                            .map(|data| {
+                                (
+                                    annotationset
+                                        .key(&data.key().into())
+                                        .and_then(|key| key.id().map(|x| x.to_owned())),
+                                    Some(TypedValue(data.value().clone())),
+                                )
+                            }),
+                    )
+                    .collect();
+                contents.sort_unstable();
+                (id.to_owned(), contents)
+            })
+        })
+        .collect();
+    (annotations, resources, annotationsets)
+}
+
+/// Compares two maps of public IDs to contents, returns a dictionary with the `added`, `removed` and `changed` IDs (sorted)
+fn diff_by_id<'py, T: PartialEq>(
+    old: &HashMap<String, T>,
+    new: &HashMap<String, T>,
+    py: Python<'py>,
+) -> PyResult<&'py PyDict> {
+    let mut added: Vec<&String> = new.keys().filter(|id| !old.contains_key(*id)).collect();
+    let mut removed: Vec<&String> = old.keys().filter(|id| !new.contains_key(*id)).collect();
+    let mut changed: Vec<&String> = old
+        .iter()
+        .filter(|(id, content)| new.get(*id).map(|x| x != *content).unwrap_or(false))
+        .map(|(id, _)| id)
+        .collect();
+    added.sort_unstable();
+    removed.sort_unstable();
+    changed.sort_unstable();
+    let result = PyDict::new(py);
+    result.set_item("added", added)?;
+    result.set_item("removed", removed)?;
+    result.set_item("changed", changed)?;
+    Ok(result)
+}
+
+/// Extracts the source IRI and the begin and end offsets from the target of a web annotation
+fn webannotation_target(target: &serde_json::Value) -> PyResult<(String, usize, usize)> {
+    let (source, selector) = match target {
+        serde_json::Value::String(iri) => {
+            let (source, fragment) = iri
+                .split_once('#')
+                .ok_or_else(|| PyStamError::new_err("Web annotation target IRI has no fragment"))?;
+            let (begin, end) = parse_char_fragment(fragment)?;
+            return Ok((source.to_string(), begin, end));
+        }
+        serde_json::Value::Object(target) => (
+            target.get("source").and_then(|source| source.as_str()),
+            target.get("selector"),
+        ),
+        _ => (None, None),
+    };
+    let source = source
+        .ok_or_else(|| PyStamError::new_err("Web annotation target has no source"))?
+        .to_string();
+    let selector =
+        selector.ok_or_else(|| PyStamError::new_err("Web annotation target has no selector"))?;
+    match selector["type"].as_str() {
+        Some("TextPositionSelector") => {
+            match (selector["start"].as_u64(), selector["end"].as_u64()) {
+                (Some(begin), Some(end)) => Ok((source, begin as usize, end as usize)),
+                _ => Err(PyStamError::new_err(
+                    "TextPositionSelector must have a start and end",
+                )),
+            }
+        }
+        Some("FragmentSelector") => {
+            let (begin, end) = parse_char_fragment(selector["value"].as_str().unwrap_or_default())?;
+            Ok((source, begin, end))
+        }
+        _ => Err(PyStamError::new_err(
+            "Web annotation selector must be a TextPositionSelector or FragmentSelector",
+        )),
+    }
+}
+
+/// Parses a `char=begin,end` fragment (RFC 5147)
+fn parse_char_fragment(fragment: &str) -> PyResult<(usize, usize)> {
+    fragment
+        .strip_prefix("char=")
+        .and_then(|range| range.split_once(','))
+        .and_then(|(begin, end)| Some((begin.parse().ok()?, end.parse().ok()?)))
+        .ok_or_else(|| {
+            PyStamError::new_err(format!(
+                "Unable to parse fragment {}, expected char=begin,end",
+                fragment
+            ))
+        })
+}
+
+fn json_into_datavalue(value: &serde_json::Value) -> PyResult<DataValue> {
+    match value {
+        serde_json::Value::String(s) => Ok(DataValue::String(s.clone())),
+        serde_json::Value::Bool(b) => Ok(DataValue::Bool(*b)),
+        serde_json::Value::Null => Ok(DataValue::Null),
+        serde_json::Value::Number(n) => {
+            if let Some(n) = n.as_i64() {
+                Ok(DataValue::Int(n as isize))
+            } else {
+                Ok(DataValue::Float(n.as_f64().unwrap_or(f64::NAN)))
+            }
+        }
+        serde_json::Value::Array(items) => Ok(DataValue::List(
+            items
+                .iter()
+                .map(json_into_datavalue)
+                .collect::<PyResult<Vec<_>>>()?,
+        )),
+        serde_json::Value::Object(_) => Err(PyStamError::new_err(
+            "Nested objects in web annotation bodies are not supported",
+        )),
+    }
+}
+
+/// Replaces references to stand-off resource files in STAM JSON by the actual text of the resources.
+/// This rewrites the JSON string in place rather than reserializing it, so the original formatting is retained.
+fn inline_resource_texts(json: &str, texts: &HashMap<String, String>) -> PyResult<String> {
+    let value: serde_json::Value = serde_json::from_str(json)
+        .map_err(|err| PyStamError::new_err(format!("Unable to parse JSON: {}", err)))?;
+    //maps the serialized value of each resource's @include to the text of that resource
+    let mut includes: Vec<(String, String)> = Vec::new();
+    if let Some(serde_json::Value::Array(resources)) = value.get("resources") {
+        for resource in resources.iter() {
+            let text = resource
+                .get("@id")
+                .and_then(|id| id.as_str())
+                .and_then(|id| texts.get(id));
+            if let (Some(include), Some(text)) = (resource.get("@include"), text) {
+                includes.push((
+                    serde_json::to_string(include).map_err(|err| {
+                        PyStamError::new_err(format!("Unable to serialize JSON: {}", err))
+                    })?,
+                    serde_json::to_string(text).map_err(|err| {
+                        PyStamError::new_err(format!("Unable to serialize JSON: {}", err))
+                    })?,
+                ));
+            }
+        }
+    }
+    const INCLUDE: &str = "\"@include\"";
+    let mut output = String::with_capacity(json.len());
+    let mut rest = json;
+    while let Some(pos) = rest.find(INCLUDE) {
+        let after = &rest[pos + INCLUDE.len()..];
+        //only object keys are followed by a colon, so this won't match inside string values
+        let value = after
+            .trim_start()
+            .strip_prefix(':')
+            .map(|value| value.trim_start());
+        let replacement = value.and_then(|value| {
+            includes
+                .iter()
+                .find(|(include, _)| value.starts_with(include.as_str()))
+                .map(|(include, text)| (&value[include.len()..], text))
+        });
+        if let Some((remainder, text)) = replacement {
+            output.push_str(&rest[..pos]);
+            output.push_str("\"text\": ");
+            output.push_str(text);
+            rest = remainder;
+        } else {
+            output.push_str(&rest[..pos + INCLUDE.len()]);
+            rest = after;
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Reserializes a JSON string with the specified indentation (minified if 0)
+fn indent_json(json: &str, indent: usize) -> PyResult<String> {
+    let value: serde_json::Value = serde_json::from_str(json)
+        .map_err(|err| PyStamError::new_err(format!("Unable to parse JSON: {}", err)))?;
+    let value = sort_json_keys(value);
+    if indent == 0 {
+        return serde_json::to_string(&value)
+            .map_err(|err| PyStamError::new_err(format!("Unable to serialize JSON: {}", err)));
+    }
+    let indentation = " ".repeat(indent);
+    let mut output = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(indentation.as_bytes());
+    let mut serializer = serde_json::Serializer::with_formatter(&mut output, formatter);
+    serde::Serialize::serialize(&value, &mut serializer)
+        .map_err(|err| PyStamError::new_err(format!("Unable to serialize JSON: {}", err)))?;
+    String::from_utf8(output)
+        .map_err(|err| PyStamError::new_err(format!("Unable to serialize JSON: {}", err)))
+}
+
+/// Reserializes a JSON string with sorted object keys and deterministic indentation
+fn canonical_json(json: &str) -> PyResult<String> {
+    let value: serde_json::Value = serde_json::from_str(json)
+        .map_err(|err| PyStamError::new_err(format!("Unable to parse JSON: {}", err)))?;
+    serde_json::to_string_pretty(&sort_json_keys(value))
+        .map_err(|err| PyStamError::new_err(format!("Unable to serialize JSON: {}", err)))
+}
+
+/// Recursively sorts the keys of all objects in a JSON value. This is done explicitly rather than relying on
+/// serde_json's map type, whose order depends on whether its `preserve_order` feature is enabled.
+fn sort_json_keys(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => {
+            let sorted: BTreeMap<String, serde_json::Value> = map
+                .into_iter()
+                .map(|(key, value)| (key, sort_json_keys(value)))
+                .collect();
+            serde_json::Value::Object(sorted.into_iter().collect())
+        }
+        serde_json::Value::Array(items) => {
+            serde_json::Value::Array(items.into_iter().map(sort_json_keys).collect())
+        }
+        value => value,
+    }
+}
+
 pub(crate) trait MapStore {
     fn get_store(&self) -> &Arc<RwLock<AnnotationStore>>;
     fn get_store_mut(&mut self) -> &mut Arc<RwLock<AnnotationStore>>;
@@ -503,6 +1980,17 @@ pub(crate) trait MapStore {
         }
     }
 
+    /// Raises a ValueError unless the other object belongs to the same store, as handles are only meaningful within one store
+    fn check_same_store(&self, other: &impl MapStore) -> PyResult<()> {
+        if Arc::ptr_eq(self.get_store(), other.get_store()) {
+            Ok(())
+        } else {
+            Err(PyValueError::new_err(
+                "Both objects must belong to the same annotation store",
+            ))
+        }
+    }
+
     fn map_store_mut<T, F>(&mut self, f: F) -> Result<T, PyErr>
     where
         F: FnOnce(&mut AnnotationStore) -> Result<T, StamError>,
@@ -579,9 +2067,320 @@ impl PyAnnotationIter {
             }
         }
     }
+
+    /// Consumes the remaining annotations and returns them as a plain list, decoupled from the live state of the store.
+    /// This is the recommended pattern if you want to modify the store (e.g. remove annotations) while processing them.
+    fn snapshot<'py>(&mut self, py: Python<'py>) -> &'py PyList {
+        let handles = self.take_remaining();
+        PyList::new(py, self.wrap_annotations(handles, py))
+    }
+
+    /// Consumes the remaining annotations and returns those for which `predicate`, a callable that takes an Annotation,
+    /// returns a true value, in a tuple. The store is not locked while the predicate runs, so it may query (or even modify)
+    /// the store.
+    fn filter<'py>(&mut self, predicate: &PyAny, py: Python<'py>) -> PyResult<&'py PyTuple> {
+        let handles = self.take_remaining();
+        let mut matches = Vec::new();
+        for handle in handles {
+            let annotation = PyAnnotation {
+                handle,
+                store: self.store.clone(),
+            };
+            if predicate.call1((annotation,))?.is_true()? {
+                matches.push(handle);
+            }
+        }
+        Ok(self.wrap_annotations(matches, py))
+    }
+
+    /// Consumes the remaining annotations and groups them by their value for `key` in annotation set `set`. Returns a
+    /// dictionary mapping each distinct value (as a native Python value, lists become tuples) to a tuple of annotations.
+    /// Annotations with multiple values for the key occur in multiple groups. Annotations without the key are grouped
+    /// under `None`, unless `skip_missing` is set.
+    #[pyo3(signature = (set, key, skip_missing=false))]
+    fn group_by<'py>(
+        &mut self,
+        set: &str,
+        key: &str,
+        skip_missing: bool,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyDict> {
+        let handles = self.take_remaining();
+        let values: Vec<(AnnotationHandle, Vec<DataValue>)> = {
+            let store = self.store.read().map_err(|_| {
+                PyRuntimeError::new_err("Unable to obtain store (should never happen)")
+            })?;
+            let stamerror = |err: StamError| PyStamError::new_err(format!("{}", err));
+            let sethandle = store.resolve_dataset_id(set).map_err(stamerror)?;
+            let annotationset: &AnnotationDataSet = store.get(sethandle).map_err(stamerror)?;
+            let keyhandle = annotationset.resolve_key_id(key).map_err(stamerror)?;
+            let mut values = Vec::new();
+            for handle in handles {
+                let annotation: &Annotation = store.get(handle).map_err(stamerror)?;
+                values.push((
+                    handle,
+                    resolve_data(&store, annotation)
+                        .filter(|(set, data)| *set == sethandle && data.key() == keyhandle)
+                        .map(|(_, data)| data.value().clone())
+                        .collect(),
+                ));
+            }
+            values
+        };
+        let groups = PyDict::new(py);
+        let mut add = |value: &PyAny, handle: AnnotationHandle| -> PyResult<()> {
+            let annotation = Py::new(
+                py,
+                PyAnnotation {
+                    handle,
+                    store: self.store.clone(),
+                },
+            )?;
+            match groups.get_item(value) {
+                Some(group) => group.downcast::<PyList>()?.append(annotation),
+                None => groups.set_item(value, PyList::new(py, [annotation])),
+            }
+        };
+        for (handle, values) in values {
+            if values.is_empty() && !skip_missing {
+                add(py.None().into_ref(py), handle)?;
+            }
+            for value in values {
+                let value = datavalue_into_py(&value, py)
+                    .map_err(|err| PyStamError::new_err(format!("{}", err)))?;
+                if let Ok(list) = value.downcast::<PyList>() {
+                    add(PyTuple::new(py, list.iter().collect::<Vec<_>>()), handle)?;
+                } else {
+                    add(value, handle)?;
+                }
+            }
+        }
+        for (value, group) in groups.copy()?.iter() {
+            let group: &PyList = group.downcast()?;
+            groups.set_item(value, PyTuple::new(py, group.iter().collect::<Vec<_>>()))?;
+        }
+        Ok(groups)
+    }
+
+    /// Consumes the remaining annotations and returns the minimal TextSelection that covers all their text spans.
+    /// All annotations must target text in the same resource; a ValueError is raised if they span multiple resources
+    /// (use `cross_resource_annotations()` to find those) or if there is no text at all.
+    fn bounding_selection(&mut self) -> PyResult<PyTextSelection> {
+        let (resource_handle, begin, end) = self.bounds()?;
+        let store = self
+            .store
+            .read()
+            .map_err(|_| PyRuntimeError::new_err("Unable to obtain store (should never happen)"))?;
+        let textselection = store
+            .get(resource_handle)
+            .and_then(|resource: &TextResource| resource.textselection(&Offset::simple(begin, end)))
+            .map_err(|err| PyStamError::new_err(format!("{}", err)))?;
+        Ok(PyTextSelection {
+            textselection,
+            resource_handle,
+            store: self.store.clone(),
+        })
+    }
+
+    /// Consumes the remaining annotations and adds a new annotation with the given data (as passed to
+    /// AnnotationStore.annotate()) on the minimal text span that covers all of them, e.g. to derive a section from its
+    /// paragraphs. Returns the new annotation. The same restrictions as for `bounding_selection()` apply.
+    #[pyo3(signature = (data, id=None))]
+    fn bounding_annotation(
+        &mut self,
+        data: Vec<&PyAny>,
+        id: Option<String>,
+    ) -> PyResult<PyAnnotation> {
+        let (resource_handle, begin, end) = self.bounds()?;
+        let mut builder = AnnotationBuilder::new().with_selector(Selector::TextSelector(
+            resource_handle,
+            Offset::simple(begin, end),
+        ));
+        if let Some(id) = id {
+            builder = builder.with_id(id);
+        }
+        {
+            let store = self.store.read().map_err(|_| {
+                PyRuntimeError::new_err("Unable to obtain store (should never happen)")
+            })?;
+            for item in data {
+                builder = builder.with_data_builder(py_into_databuilder(item, &store)?);
+            }
+        }
+        let mut store = self
+            .store
+            .write()
+            .map_err(|_| PyRuntimeError::new_err("Can't get exclusive lock to write to store"))?;
+        let handle = store
+            .annotate(builder)
+            .map_err(|err| PyStamError::new_err(format!("{}", err)))?;
+        Ok(PyAnnotation {
+            handle,
+            store: self.store.clone(),
+        })
+    }
+
+    /// Consumes the remaining annotations and returns those that have data with the specified key and a numeric value within the
+    /// specified range (inclusive). Either bound may be omitted. Returns a tuple.
+    #[pyo3(signature = (key, min=None, max=None))]
+    fn filter_score<'py>(
+        &mut self,
+        key: PyRef<PyDataKey>,
+        min: Option<f64>,
+        max: Option<f64>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let handles = self.take_remaining();
+        let store = self
+            .store
+            .read()
+            .map_err(|_| PyRuntimeError::new_err("Unable to obtain store (should never happen)"))?;
+        let mut matches = Vec::new();
+        for handle in handles {
+            let annotation: &Annotation = store
+                .get(handle)
+                .map_err(|err| PyStamError::new_err(format!("{}", err)))?;
+            let found = resolve_data(&store, annotation).any(|(set, data)| {
+                if set != key.set || data.key() != key.handle {
+                    return false;
+                }
+                let score = match data.value() {
+                    DataValue::Int(v) => *v as f64,
+                    DataValue::Float(v) => *v,
+                    _ => return false,
+                };
+                min.map_or(true, |min| score >= min) && max.map_or(true, |max| score <= max)
+            });
+            if found {
+                matches.push(handle);
+            }
+        }
+        Ok(self.wrap_annotations(matches, py))
+    }
+
+    /// Consumes the remaining annotations and returns a dictionary mapping each distinct text to the number of annotations
+    /// with that text. The text of annotations referencing multiple text slices is joined with a space, as in `str()`.
+    /// If `case_sensitive` is False, texts are lowercased. `normalize` can be a name or list of names of further normalizations
+    /// to apply: "strip" (remove leading and trailing whitespace) and "whitespace" (collapse all whitespace into a single space).
+    #[pyo3(signature = (case_sensitive=true, normalize=None))]
+    fn text_frequencies(
+        &mut self,
+        case_sensitive: bool,
+        normalize: Option<&PyAny>,
+    ) -> PyResult<HashMap<String, usize>> {
+        let normalize: Vec<String> = match normalize {
+            None => Vec::new(),
+            Some(normalize) => {
+                if let Ok(name) = normalize.extract::<String>() {
+                    vec![name]
+                } else {
+                    normalize.extract()?
+                }
+            }
+        };
+        for name in normalize.iter() {
+            if name != "strip" && name != "whitespace" {
+                return Err(PyValueError::new_err(format!(
+                    "Unknown normalization: {} (expected \"strip\" or \"whitespace\")",
+                    name
+                )));
+            }
+        }
+        let handles = self.take_remaining();
+        let store = self
+            .store
+            .read()
+            .map_err(|_| PyRuntimeError::new_err("Unable to obtain store (should never happen)"))?;
+        let mut frequencies: HashMap<String, usize> = HashMap::new();
+        for handle in handles {
+            let annotation: &Annotation = store
+                .get(handle)
+                .map_err(|err| PyStamError::new_err(format!("{}", err)))?;
+            let mut text = store
+                .text_by_annotation(annotation)
+                .collect::<Vec<&str>>()
+                .join(" ");
+            if !case_sensitive {
+                text = text.to_lowercase();
+            }
+            for name in normalize.iter() {
+                text = match name.as_str() {
+                    "strip" => text.trim().to_string(),
+                    _ => text.split_whitespace().collect::<Vec<&str>>().join(" "),
+                };
+            }
+            *frequencies.entry(text).or_default() += 1;
+        }
+        Ok(frequencies)
+    }
 }
 
 impl PyAnnotationIter {
+    /// Consumes all remaining annotations of this iterator (under a single lock) and returns their handles
+    fn take_remaining(&mut self) -> Vec<AnnotationHandle> {
+        let mut handles = Vec::new();
+        if let Ok(store) = self.store.read() {
+            let len = store.annotations_len();
+            for i in self.index..len {
+                if let Some(annotation) = store.annotation_by_index(i) {
+                    handles.push(annotation.handle().expect("annotation must have an ID"));
+                }
+            }
+            self.index = len;
+        }
+        handles
+    }
+
+    /// Consumes all remaining annotations and computes the span (resource, begin, end) that covers all their text
+    fn bounds(&mut self) -> PyResult<(TextResourceHandle, usize, usize)> {
+        let handles = self.take_remaining();
+        let store = self
+            .store
+            .read()
+            .map_err(|_| PyRuntimeError::new_err("Unable to obtain store (should never happen)"))?;
+        let mut bounds: Option<(TextResourceHandle, usize, usize)> = None;
+        for handle in handles {
+            let annotation: &Annotation = store
+                .get(handle)
+                .map_err(|err| PyStamError::new_err(format!("{}", err)))?;
+            for (resource_handle, begin, end) in sorted_spans(&store, annotation) {
+                bounds = match bounds {
+                    None => Some((resource_handle, begin, end)),
+                    Some((bounds_resource, _, _)) if bounds_resource != resource_handle => {
+                        return Err(PyValueError::new_err(
+                            "Annotations reference text in multiple resources, bounds can only be computed for a single resource",
+                        ));
+                    }
+                    Some((_, boundsbegin, boundsend)) => {
+                        Some((resource_handle, boundsbegin.min(begin), boundsend.max(end)))
+                    }
+                };
+            }
+        }
+        bounds.ok_or_else(|| PyValueError::new_err("Annotations do not reference any text"))
+    }
+
+    fn wrap_annotations<'py>(
+        &self,
+        handles: Vec<AnnotationHandle>,
+        py: Python<'py>,
+    ) -> &'py PyTuple {
+        let elements: Vec<Py<PyAnnotation>> = handles
+            .into_iter()
+            .map(|handle| {
+                Py::new(
+                    py,
+                    PyAnnotation {
+                        handle,
+                        store: self.store.clone(),
+                    },
+                )
+                .expect("wrapping PyAnnotation")
+            })
+            .collect();
+        PyTuple::new(py, elements)
+    }
+
     fn map<T, F>(&self, f: F) -> Option<T>
     where
         F: FnOnce(&AnnotationStore) -> Option<T>,
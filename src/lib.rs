@@ -8,7 +8,7 @@ mod error;
 mod resources;
 mod selector;
 
-use crate::annotation::PyAnnotation;
+use crate::annotation::{PyAnnotation, PyAnnotationBuilder};
 use crate::annotationdata::{PyAnnotationData, PyAnnotationDataBuilder, PyDataKey, PyDataValue};
 use crate::annotationdataset::PyAnnotationDataSet;
 use crate::annotationstore::PyAnnotationStore;
@@ -24,6 +24,7 @@ fn stam(py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyAnnotationData>()?;
     m.add_class::<PyAnnotationDataBuilder>()?;
     m.add_class::<PyAnnotation>()?;
+    m.add_class::<PyAnnotationBuilder>()?;
     m.add_class::<PyDataKey>()?;
     m.add_class::<PyDataValue>()?;
     m.add_class::<PyTextResource>()?;
@@ -1,10 +1,20 @@
+use aho_corasick::{AhoCorasickBuilder, MatchKind};
 use pyo3::exceptions::{PyException, PyIndexError, PyKeyError, PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
 use pyo3::pyclass::CompareOp;
 use pyo3::types::*;
+use regex::Regex;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::ops::FnOnce;
 use std::sync::{Arc, RwLock};
+use unicode_bidi::BidiClass;
+use unicode_script::{Script, UnicodeScript};
 
+use crate::annotation::{fnv1a, has_data, resolve_data, sorted_spans, PyAnnotation, SpanRelation};
+use crate::annotationdata::{
+    py_into_databuilder, py_into_datavalue, PyAnnotationDataBuilder, PyDataKey,
+};
 use crate::annotationstore::MapStore;
 use crate::error::PyStamError;
 use crate::selector::PySelector;
@@ -39,6 +49,12 @@ impl PyTextResource {
         }
     }
 
+    /// Returns the encoding of the text. This is always "utf-8", as text is decoded at load time
+    /// (see the `encoding` argument of `AnnotationStore.add_resource()`).
+    fn encoding(&self) -> &'static str {
+        "utf-8"
+    }
+
     /// Returns the full text of the resource (by value, aka a copy)
     fn __str__<'py>(&self, py: Python<'py>) -> PyResult<&'py PyString> {
         self.map(|resource| Ok(PyString::new(py, resource.text())))
@@ -53,6 +69,18 @@ impl PyTextResource {
         }
     }
 
+    /// Converts a position in unicode points to the corresponding position in UTF-16 code units (as used by JavaScript).
+    /// Raises an IndexError if the position is beyond the end of the text.
+    fn utf16unit(&self, abscursor: usize) -> PyResult<usize> {
+        self.map(|res| Ok(charpos_to_utf16unit(res.text(), abscursor)))?
+    }
+
+    /// Converts a position in UTF-16 code units (as used by JavaScript) to the corresponding position in unicode points.
+    /// Raises an IndexError if the position is beyond the end of the text or in the middle of a surrogate pair.
+    fn utf16unit_to_charpos(&self, unit: usize) -> PyResult<usize> {
+        self.map(|res| Ok(utf16unit_to_charpos(res.text(), unit)))?
+    }
+
     /// Returns a TextSelection instance referring to the specified offset
     fn textselection(&self, offset: &PyOffset) -> PyResult<PyTextSelection> {
         self.map(|res| Ok(self.wrap_textselection(res.textselection(&offset.offset)?)))
@@ -76,37 +104,698 @@ impl PyTextResource {
                 .unwrap(),
             index: 0,
             subindex: 0,
+            reverse: false,
             resource_handle: self.handle,
             store: self.store.clone(),
         }
     }
 
+    /// Returns a list of the names of all Unicode scripts present in the text, in order of first appearance.
+    /// Characters shared by scripts (spaces, punctuation, digits) are not counted.
+    fn scripts(&self) -> PyResult<Vec<&'static str>> {
+        self.map(|res| {
+            let mut scripts: Vec<Script> = Vec::new();
+            for c in res.text().chars() {
+                let script = c.script();
+                match script {
+                    Script::Common | Script::Inherited | Script::Unknown => {}
+                    _ => {
+                        if !scripts.contains(&script) {
+                            scripts.push(script);
+                        }
+                    }
+                }
+            }
+            Ok(scripts
+                .into_iter()
+                .map(|script| script.full_name())
+                .collect())
+        })
+    }
+
+    /// Splits the text of the resource on a regular expression, returns a tuple of TextSelections for the parts in between
+    /// the matches. Empty parts are omitted. If `limit` is set, at most that many parts are returned, the last of which
+    /// contains the unsplit remainder.
+    #[pyo3(signature = (pattern, limit=None))]
+    fn split_text_regex<'py>(
+        &self,
+        pattern: &str,
+        limit: Option<usize>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let regex = compile_regex(pattern)?;
+        let textselections = self.map(|res| split_regex(res, 0, res.textlen(), &regex, limit))?;
+        Ok(self.wrap_textselections(textselections, py))
+    }
+
+    /// Searches the text of the resource for all (non-overlapping) occurrences of a fragment, returns a tuple of TextSelections.
+    /// With `fold` set to "case", matching is case-insensitive (using unicode lowercasing), with "case+diacritics" diacritics
+    /// are ignored as well (e.g. "café" matches "Cafe"). The offsets always refer to the original text.
+    /// If `within` is set to an annotation (or its public ID), only matches embedded in the text of that annotation are
+    /// returned. If `outside` is set, only matches that do not overlap with the text of that annotation are returned.
+    #[pyo3(signature = (fragment, fold="none", within=None, outside=None))]
+    fn find_text<'py>(
+        &self,
+        fragment: &str,
+        fold: &str,
+        within: Option<&PyAny>,
+        outside: Option<&PyAny>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let within = within
+            .map(|annotation| self.spans_of_annotation(annotation))
+            .transpose()?;
+        let outside = outside
+            .map(|annotation| self.spans_of_annotation(annotation))
+            .transpose()?;
+        let (case, diacritics) = match fold {
+            "none" => (false, false),
+            "case" => (true, false),
+            "case+diacritics" => (true, true),
+            _ => {
+                return Err(PyValueError::new_err(
+                    "fold must be \"none\", \"case\" or \"case+diacritics\"",
+                ))
+            }
+        };
+        let textselections = self.map(|res| {
+            let text = res.text();
+            if fragment.is_empty() {
+                return Ok(Vec::new());
+            }
+            let mut offsets = Vec::new();
+            if !case && !diacritics {
+                let mut converter = CharPosConverter::new(text);
+                for (bytepos, m) in text.match_indices(fragment) {
+                    let begin = converter.charpos(bytepos);
+                    let end = converter.charpos(bytepos + m.len());
+                    offsets.push((begin, end));
+                }
+            } else {
+                let (folded, origins) = fold_text(text, diacritics);
+                let (fragment, _) = fold_text(fragment, diacritics);
+                if fragment.is_empty() {
+                    return Ok(Vec::new());
+                }
+                let mut converter = CharPosConverter::new(&folded);
+                for (bytepos, m) in folded.match_indices(fragment.as_str()) {
+                    let begin = converter.charpos(bytepos);
+                    let end = converter.charpos(bytepos + m.len());
+                    offsets.push((origins[begin], origins[end - 1] + 1));
+                }
+            }
+            offsets
+                .into_iter()
+                .filter(|(begin, end)| {
+                    within.as_ref().map_or(true, |spans| {
+                        spans
+                            .iter()
+                            .any(|(spanbegin, spanend)| spanbegin <= begin && end <= spanend)
+                    }) && outside.as_ref().map_or(true, |spans| {
+                        !spans
+                            .iter()
+                            .any(|(spanbegin, spanend)| spanbegin < end && begin < spanend)
+                    })
+                })
+                .map(|(begin, end)| res.textselection(&Offset::simple(begin, end)))
+                .collect()
+        })?;
+        Ok(self.wrap_textselections(textselections, py))
+    }
+
+    /// Searches the text of the resource for a regular expression and returns a tuple of TextSelections for all matches.
+    /// If `named_groups` is set, a list with a dictionary per match is returned instead, mapping the names of the
+    /// named capture groups (e.g. `(?P<year>\d{4})`) to TextSelections, or to None if the group did not participate in the match.
+    #[pyo3(signature = (pattern, named_groups=false))]
+    fn find_text_regex(&self, pattern: &str, named_groups: bool, py: Python) -> PyResult<PyObject> {
+        let regex = compile_regex(pattern)?;
+        if !named_groups {
+            let textselections = self.map(|res| {
+                let text = res.text();
+                let mut converter = CharPosConverter::new(text);
+                regex
+                    .find_iter(text)
+                    .map(|m| {
+                        let begin = converter.charpos(m.start());
+                        let end = converter.charpos(m.end());
+                        res.textselection(&Offset::simple(begin, end))
+                    })
+                    .collect::<Result<Vec<_>, StamError>>()
+            })?;
+            return Ok(self.wrap_textselections(textselections, py).into());
+        }
+        let names: Vec<&str> = regex.capture_names().flatten().collect();
+        let matches = self.map(|res| {
+            let text = res.text();
+            let mut converter = CharPosConverter::new(text);
+            let mut matches: Vec<Vec<(&str, Option<TextSelection>)>> = Vec::new();
+            for captures in regex.captures_iter(text) {
+                let mut groups = Vec::with_capacity(names.len());
+                for name in names.iter() {
+                    let textselection = if let Some(m) = captures.name(name) {
+                        let begin = converter.charpos(m.start());
+                        let end = converter.charpos(m.end());
+                        Some(res.textselection(&Offset::simple(begin, end))?)
+                    } else {
+                        None
+                    };
+                    groups.push((*name, textselection));
+                }
+                matches.push(groups);
+            }
+            Ok(matches)
+        })?;
+        let result = PyList::empty(py);
+        for groups in matches {
+            let dict = PyDict::new(py);
+            for (name, textselection) in groups {
+                let textselection = textselection
+                    .map(|textselection| {
+                        Py::new(
+                            py,
+                            PyTextSelection {
+                                textselection,
+                                resource_handle: self.handle,
+                                store: self.store.clone(),
+                            },
+                        )
+                    })
+                    .transpose()?;
+                dict.set_item(name, textselection)?;
+            }
+            result.append(dict)?;
+        }
+        Ok(result.into())
+    }
+
+    /// Finds all matches of a regular expression in the text of the resource and annotates each of them with the given data,
+    /// which is passed as to AnnotationStore.annotate(). Returns a tuple of the new annotations.
+    /// If `group` is set (the index or name of a capture group), only that group is annotated rather than the whole match;
+    /// matches in which the group does not participate are skipped. If `limit` is set, at most that many matches are annotated.
+    /// If `id_prefix` is set, annotations get an ID consisting of that prefix and the index of the annotation in the store.
+    #[pyo3(signature = (pattern, data, group=None, limit=None, id_prefix=None))]
+    fn annotate_regex<'py>(
+        &mut self,
+        pattern: &str,
+        data: Vec<&PyAny>,
+        group: Option<&PyAny>,
+        limit: Option<usize>,
+        id_prefix: Option<&str>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let regex = compile_regex(pattern)?;
+        let group: Option<Result<usize, String>> = match group {
+            None => None,
+            Some(group) => Some(if let Ok(index) = group.extract::<usize>() {
+                Ok(index)
+            } else {
+                Err(group.extract::<String>()?)
+            }),
+        };
+        let offsets = self.map(|res| {
+            let text = res.text();
+            let mut converter = CharPosConverter::new(text);
+            let mut offsets = Vec::new();
+            for captures in regex.captures_iter(text) {
+                if limit.map_or(false, |limit| offsets.len() >= limit) {
+                    break;
+                }
+                let m = match group.as_ref() {
+                    None => captures.get(0),
+                    Some(Ok(index)) => captures.get(*index),
+                    Some(Err(name)) => captures.name(name),
+                };
+                if let Some(m) = m {
+                    let begin = converter.charpos(m.start());
+                    let end = converter.charpos(m.end());
+                    offsets.push(Offset::simple(begin, end));
+                }
+            }
+            Ok(offsets)
+        })?;
+        self.annotate_offsets(offsets, data, id_prefix, py)
+    }
+
+    /// Proposes a correction without editing the text: finds all (non-overlapping) occurrences of `find` in the text of
+    /// the resource and annotates each of them with the suggested replacement as the value of `key` in `annotationset`.
+    /// Any further `data` is added to each annotation as well. Returns a tuple of the new annotations.
+    #[pyo3(signature = (find, replace, data=None, annotationset="corrections", key="replacement", id_prefix=None))]
+    fn suggest_replacement<'py>(
+        &mut self,
+        find: &str,
+        replace: &str,
+        data: Option<Vec<&'py PyAny>>,
+        annotationset: &str,
+        key: &str,
+        id_prefix: Option<&str>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        if find.is_empty() {
+            return Err(PyValueError::new_err("find may not be empty"));
+        }
+        let offsets = self.map(|res| {
+            let text = res.text();
+            let mut converter = CharPosConverter::new(text);
+            Ok(text
+                .match_indices(find)
+                .map(|(bytepos, m)| {
+                    Offset::simple(
+                        converter.charpos(bytepos),
+                        converter.charpos(bytepos + m.len()),
+                    )
+                })
+                .collect::<Vec<_>>())
+        })?;
+        let mut data = data.unwrap_or_default();
+        let replacement = PyAnnotationDataBuilder::new(
+            annotationset.to_string(),
+            key.to_string(),
+            replace.into_py(py).into_ref(py),
+            None,
+        )?;
+        data.push(Py::new(py, replacement)?.into_ref(py));
+        self.annotate_offsets(offsets, data, id_prefix, py)
+    }
+
+    /// Segments the text of the resource into sentences, returns a tuple of TextSelections.
+    /// The default segmenter splits after sentence-final punctuation that is followed by whitespace.
+    /// A custom `segmenter` can be passed, a callable that takes a string and returns (begin, end) tuples (in unicode points).
+    /// A ValueError is raised if any of those is not within the string.
+    #[pyo3(signature = (segmenter=None))]
+    fn sentences<'py>(&self, segmenter: Option<&PyAny>, py: Python<'py>) -> PyResult<&'py PyTuple> {
+        let textlen = self.map(|res| Ok(res.textlen()))?;
+        segment(
+            &self.store,
+            self.handle,
+            0,
+            textlen,
+            Segmentation::Sentences,
+            segmenter,
+            py,
+        )
+    }
+
+    /// Segments the text of the resource into words, returns a tuple of TextSelections.
+    /// The default segmenter returns all consecutive runs of alphanumeric characters.
+    /// A custom `segmenter` can be passed, a callable that takes a string and returns (begin, end) tuples (in unicode points).
+    /// A ValueError is raised if any of those is not within the string.
+    #[pyo3(signature = (segmenter=None))]
+    fn words<'py>(&self, segmenter: Option<&PyAny>, py: Python<'py>) -> PyResult<&'py PyTuple> {
+        let textlen = self.map(|res| Ok(res.textlen()))?;
+        segment(
+            &self.store,
+            self.handle,
+            0,
+            textlen,
+            Segmentation::Words,
+            segmenter,
+            py,
+        )
+    }
+
+    /// Searches for any of the specified text fragments in a single pass over the text (using an Aho-Corasick automaton),
+    /// which is much faster than searching for each fragment separately.
+    /// Returns a list of (TextSelection, index) tuples, where index refers to the fragment that matched.
+    /// If `case_sensitive` is false, matching is case insensitive for ASCII characters only.
+    /// If `allow_overlap` is false, the longest leftmost match is returned whenever matches overlap.
+    #[pyo3(signature = (fragments, case_sensitive=true, allow_overlap=false))]
+    fn find_any_text(
+        &self,
+        fragments: Vec<&str>,
+        case_sensitive: bool,
+        allow_overlap: bool,
+    ) -> PyResult<Vec<(PyTextSelection, usize)>> {
+        let automaton = AhoCorasickBuilder::new()
+            .ascii_case_insensitive(!case_sensitive)
+            .match_kind(if allow_overlap {
+                MatchKind::Standard
+            } else {
+                MatchKind::LeftmostLongest
+            })
+            .build(fragments);
+        self.map(|res| {
+            let text = res.text();
+            let mut matches: Vec<(usize, usize, usize)> = if allow_overlap {
+                automaton
+                    .find_overlapping_iter(text)
+                    .map(|m| (m.start(), m.end(), m.pattern()))
+                    .collect()
+            } else {
+                automaton
+                    .find_iter(text)
+                    .map(|m| (m.start(), m.end(), m.pattern()))
+                    .collect()
+            };
+            //overlapping matches are reported in order of their end, the converter is fastest in order of begin
+            matches.sort_unstable();
+            let mut converter = CharPosConverter::new(text);
+            let mut results = Vec::with_capacity(matches.len());
+            for (begin, end, fragment) in matches {
+                let begin = converter.charpos(begin);
+                let end = converter.charpos(end);
+                results.push((
+                    self.wrap_textselection(res.textselection(&Offset::simple(begin, end))?),
+                    fragment,
+                ));
+            }
+            Ok(results)
+        })
+    }
+
+    /// Returns the number of annotations that reference this resource, either its text or the resource as a whole.
+    /// This is a lookup in the reverse index, no annotations are instantiated.
+    fn annotations_len(&self) -> PyResult<usize> {
+        self.map_store(|store| {
+            Ok(store
+                .annotations_by_resource(self.handle)
+                .into_iter()
+                .flatten()
+                .count())
+        })
+    }
+
+    /// Returns a tuple of the annotations that reference this resource, either its text or the resource as a whole.
+    /// If `range` is set to a (begin, end) tuple (in unicode points), only annotations with a text span that intersects
+    /// that range are returned, in textual order. This uses the position index rather than inspecting every annotation on
+    /// the resource, making it suitable for fetching the annotations of the visible part of a large text.
+    #[pyo3(signature = (range=None))]
+    fn annotations<'py>(
+        &self,
+        range: Option<(usize, usize)>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let handles = self.map_store(|store| {
+            let (begin, end) = match range {
+                Some(range) => range,
+                None => {
+                    return Ok(store
+                        .annotations_by_resource(self.handle)
+                        .into_iter()
+                        .flatten()
+                        .collect::<Vec<_>>())
+                }
+            };
+            let resource: &TextResource = store.get(self.handle)?;
+            let mut handles = Vec::new();
+            for position in resource.positions().take_while(|position| **position < end) {
+                if let Some(positionitem) = resource.position(*position) {
+                    for (_, handle) in positionitem.iter_begin2end() {
+                        let textselection: &TextSelection = resource.get(*handle)?;
+                        if textselection.end() > begin {
+                            for annotation in store
+                                .annotations_by_textselection(self.handle, textselection)
+                                .into_iter()
+                                .flatten()
+                            {
+                                if !handles.contains(annotation) {
+                                    handles.push(*annotation);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Ok(handles)
+        })?;
+        let elements: Vec<Py<PyAnnotation>> = handles
+            .into_iter()
+            .map(|handle| {
+                Py::new(
+                    py,
+                    PyAnnotation {
+                        handle,
+                        store: self.store.clone(),
+                    },
+                )
+                .expect("wrapping PyAnnotation")
+            })
+            .collect();
+        Ok(PyTuple::new(py, elements))
+    }
+
+    /// Tests whether any annotation references this resource, either its text or the resource as a whole.
+    /// This is a cheap lookup in the reverse index that stops at the first annotation found.
+    fn has_annotations(&self) -> PyResult<bool> {
+        self.map_store(|store| {
+            Ok(store
+                .annotations_by_resource(self.handle)
+                .into_iter()
+                .flatten()
+                .next()
+                .is_some())
+        })
+    }
+
+    /// Returns the number of annotations that reference this resource as a whole (i.e. via a ResourceSelector),
+    /// rather than any text in it. These annotations describe metadata of the resource.
+    fn annotations_as_metadata_len(&self) -> PyResult<usize> {
+        self.map_store(|store| {
+            let mut count = 0;
+            for handle in store
+                .annotations_by_resource(self.handle)
+                .into_iter()
+                .flatten()
+            {
+                let annotation: &Annotation = store.get(handle)?;
+                if let Selector::ResourceSelector(reshandle) = annotation.target() {
+                    if *reshandle == self.handle {
+                        count += 1;
+                    }
+                }
+            }
+            Ok(count)
+        })
+    }
+
+    /// Returns the text of the resource with markers inserted around the spans of the annotations on it, for quick inspection.
+    /// If `key` is given, only annotations with data for that key are marked, and if `value` is given as well, the data must
+    /// also have that value. If `label` is given (a DataKey), the value the annotation has for that key is added before the
+    /// closing marker, separated by a `|`. Discontinuous annotations are marked from their first to their last character.
+    ///
+    /// Nested spans result in nested markers, with the outer span opened first and closed last.
+    /// Spans that partially overlap can not be nested and have their markers inserted in order of position.
+    #[pyo3(signature = (key=None, value=None, open="[", close="]", label=None))]
+    fn to_markup(
+        &self,
+        key: Option<PyRef<PyDataKey>>,
+        value: Option<&PyAny>,
+        open: &str,
+        close: &str,
+        label: Option<PyRef<PyDataKey>>,
+    ) -> PyResult<String> {
+        let value = value
+            .map(py_into_datavalue)
+            .transpose()
+            .map_err(|err| PyStamError::new_err(format!("{}", err)))?;
+        self.map_store(|store| {
+            let mut spans: Vec<(usize, usize, Option<String>)> = Vec::new();
+            for (handle, begin, end) in annotation_spans(store, self.handle)? {
+                let annotation: &Annotation = store.get(handle)?;
+                if begin == end || !has_data(store, annotation, key.as_deref(), value.as_ref()) {
+                    continue;
+                }
+                let label = label.as_ref().and_then(|label| {
+                    resolve_data(store, annotation)
+                        .find(|(set, data)| *set == label.set && data.key() == label.handle)
+                        .map(|(_, data)| data.value().to_string())
+                });
+                spans.push((begin, end, label));
+            }
+            spans.sort_by_key(|(begin, end, _)| (*begin, std::cmp::Reverse(*end)));
+            //events are (position, is_open, order), closing markers come before opening ones at the same position
+            //and close in the reverse order they were opened
+            let mut events: Vec<(usize, bool, isize)> = Vec::with_capacity(spans.len() * 2);
+            for (i, (begin, end, _)) in spans.iter().enumerate() {
+                events.push((*begin, true, i as isize));
+                events.push((*end, false, -(i as isize)));
+            }
+            events.sort_unstable();
+            let resource: &TextResource = store.get(self.handle)?;
+            let mut markup = String::with_capacity(resource.text().len());
+            let mut events = events.into_iter().peekable();
+            for (charpos, c) in resource
+                .text()
+                .chars()
+                .map(Some)
+                .chain(std::iter::once(None))
+                .enumerate()
+            {
+                while let Some((_, is_open, order)) = events.next_if(|(pos, _, _)| *pos == charpos)
+                {
+                    if is_open {
+                        markup.push_str(open);
+                    } else {
+                        if let Some(label) = spans[(-order) as usize].2.as_ref() {
+                            markup.push('|');
+                            markup.push_str(label);
+                        }
+                        markup.push_str(close);
+                    }
+                }
+                if let Some(c) = c {
+                    markup.push(c);
+                }
+            }
+            Ok(markup)
+        })
+    }
+
+    /// Assigns each annotation on this resource to a lane, such that annotations with overlapping spans are
+    /// never in the same lane. This is what is needed to render annotations as a timeline.
+    /// Returns a list of (annotation, lane) tuples, ordered by the begin of the annotations.
+    fn layers(&self) -> PyResult<Vec<(PyAnnotation, usize)>> {
+        self.map_store(|store| {
+            let mut spans = annotation_spans(store, self.handle)?;
+            spans.sort_unstable_by_key(|(handle, begin, end)| {
+                (*begin, std::cmp::Reverse(*end), *handle)
+            });
+            //holds the end of the last span in each lane
+            let mut lanes: Vec<usize> = Vec::new();
+            let mut result = Vec::with_capacity(spans.len());
+            for (handle, begin, end) in spans {
+                let lane = if let Some(lane) = lanes.iter().position(|laneend| *laneend <= begin) {
+                    lanes[lane] = end;
+                    lane
+                } else {
+                    lanes.push(end);
+                    lanes.len() - 1
+                };
+                result.push((
+                    PyAnnotation {
+                        handle,
+                        store: self.store.clone(),
+                    },
+                    lane,
+                ));
+            }
+            Ok(result)
+        })
+    }
+
+    /// Returns, for each of the given positions (in unicode points), a tuple of all known text selections that cover it.
+    /// This is resolved in a single pass over the position index, which is much faster than separate lookups per position.
+    fn selections_covering<'py>(
+        &self,
+        positions: Vec<usize>,
+        py: Python<'py>,
+    ) -> PyResult<Vec<&'py PyTuple>> {
+        let covering = self.map(|res| {
+            let mut textselections: Vec<TextSelection> = Vec::new();
+            for position in res.positions() {
+                if let Some(positionitem) = res.position(*position) {
+                    for (_, handle) in positionitem.iter_begin2end() {
+                        let textselection: &TextSelection = res.get(*handle)?;
+                        textselections.push(textselection.clone());
+                    }
+                }
+            }
+            textselections.sort_by_key(|textselection| textselection.begin());
+            let mut order: Vec<usize> = (0..positions.len()).collect();
+            order.sort_by_key(|i| positions[*i]);
+            let mut covering: Vec<Vec<TextSelection>> = vec![Vec::new(); positions.len()];
+            let mut active: Vec<&TextSelection> = Vec::new();
+            let mut next = 0;
+            for i in order {
+                let position = positions[i];
+                while next < textselections.len() && textselections[next].begin() <= position {
+                    active.push(&textselections[next]);
+                    next += 1;
+                }
+                active.retain(|textselection| textselection.end() > position);
+                covering[i] = active.iter().map(|x| (*x).clone()).collect();
+            }
+            Ok(covering)
+        })?;
+        Ok(covering
+            .into_iter()
+            .map(|textselections| self.wrap_textselections(textselections, py))
+            .collect())
+    }
+
     /// Iterates over all known textselections that start in the spceified range, in sorted order
+    /// If `reverse` is set, iterates in reverse sorted order instead.
     //TODO:  this should be __getitem__() with a proper python slice
-    fn range(&self, begin: usize, end: usize) -> PyResult<PyTextSelectionIter> {
+    #[pyo3(signature = (begin, end, reverse=false))]
+    fn range(&self, begin: usize, end: usize, reverse: bool) -> PyResult<PyTextSelectionIter> {
+        let mut positions = self
+            .map(|res| {
+                Ok(res
+                    .positions()
+                    .filter_map(|x| {
+                        if *x >= begin && *x < end {
+                            Some(*x)
+                        } else {
+                            None
+                        }
+                    })
+                    .collect::<Vec<usize>>())
+            })
+            .unwrap();
+        if reverse {
+            positions.reverse();
+        }
         Ok(PyTextSelectionIter {
-            positions: self
-                .map(|res| {
-                    Ok(res
-                        .positions()
-                        .filter_map(|x| {
-                            if *x >= begin && *x < end {
-                                Some(*x)
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<usize>>())
-                })
-                .unwrap(),
+            positions,
             index: 0,
             subindex: 0,
+            reverse,
             resource_handle: self.handle,
             store: self.store.clone(),
         })
     }
 }
 
+impl MapStore for PyTextResource {
+    fn get_store(&self) -> &Arc<RwLock<AnnotationStore>> {
+        &self.store
+    }
+    fn get_store_mut(&mut self) -> &mut Arc<RwLock<AnnotationStore>> {
+        &mut self.store
+    }
+}
+
+impl MapStore for PyTextSelection {
+    fn get_store(&self) -> &Arc<RwLock<AnnotationStore>> {
+        &self.store
+    }
+    fn get_store_mut(&mut self) -> &mut Arc<RwLock<AnnotationStore>> {
+        &mut self.store
+    }
+}
+
+/// Collects all annotations referencing text in the specified resource, along with the begin and end of the span
+/// they cover in that resource. Annotations referencing multiple parts of the resource get the span from the
+/// first begin to the last end.
+pub(crate) fn annotation_spans(
+    store: &AnnotationStore,
+    resource_handle: TextResourceHandle,
+) -> Result<Vec<(AnnotationHandle, usize, usize)>, StamError> {
+    let mut spans = Vec::new();
+    for handle in store
+        .annotations_by_resource(resource_handle)
+        .into_iter()
+        .flatten()
+    {
+        let annotation: &Annotation = store.get(handle)?;
+        let mut span: Option<(usize, usize)> = None;
+        for (reshandle, textselection) in store.textselections_by_annotation(annotation) {
+            if reshandle == resource_handle {
+                span = Some(match span {
+                    Some((begin, end)) => (
+                        begin.min(textselection.begin()),
+                        end.max(textselection.end()),
+                    ),
+                    None => (textselection.begin(), textselection.end()),
+                });
+            }
+        }
+        if let Some((begin, end)) = span {
+            spans.push((handle, begin, end));
+        }
+    }
+    Ok(spans)
+}
+
 impl PyTextResource {
     /// Map function to act on the actual underlying store, helps reduce boilerplate
     fn map<T, F>(&self, f: F) -> Result<T, PyErr>
@@ -125,6 +814,74 @@ impl PyTextResource {
         }
     }
 
+    /// Annotates each of the offsets in this resource with the given data (as passed to AnnotationStore.annotate()),
+    /// returns a tuple of the new annotations.
+    fn annotate_offsets<'py>(
+        &mut self,
+        offsets: Vec<Offset>,
+        data: Vec<&PyAny>,
+        id_prefix: Option<&str>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let builders = self.map_store(|store| {
+            let index = store.annotations_len();
+            Ok(offsets
+                .into_iter()
+                .enumerate()
+                .map(|(i, offset)| {
+                    let mut builder = AnnotationBuilder::new()
+                        .with_selector(Selector::TextSelector(self.handle, offset));
+                    if let Some(id_prefix) = id_prefix {
+                        builder = builder.with_id(format!("{}{}", id_prefix, index + i));
+                    }
+                    for item in data.iter() {
+                        builder = builder.with_data_builder(py_into_databuilder(item, store)?);
+                    }
+                    Ok(builder)
+                })
+                .collect::<Vec<PyResult<AnnotationBuilder>>>())
+        })?;
+        let builders = builders.into_iter().collect::<PyResult<Vec<_>>>()?;
+        let handles = self.map_store_mut(|store| {
+            builders
+                .into_iter()
+                .map(|builder| store.annotate(builder))
+                .collect::<Result<Vec<_>, StamError>>()
+        })?;
+        let elements: Vec<Py<PyAnnotation>> = handles
+            .into_iter()
+            .map(|handle| {
+                Py::new(
+                    py,
+                    PyAnnotation {
+                        handle,
+                        store: self.store.clone(),
+                    },
+                )
+                .expect("wrapping PyAnnotation")
+            })
+            .collect();
+        Ok(PyTuple::new(py, elements))
+    }
+
+    /// Returns the text spans (begin, end) an annotation, given as an Annotation or its public ID, has in this resource
+    fn spans_of_annotation(&self, annotation: &PyAny) -> PyResult<Vec<(usize, usize)>> {
+        let handle = if let Ok(annotation) = annotation.extract::<PyRef<PyAnnotation>>() {
+            annotation.handle
+        } else {
+            let id: &str = annotation.extract()?;
+            self.map_store(|store| store.resolve_annotation_id(id))?
+        };
+        self.map_store(|store| {
+            let annotation: &Annotation = store.get(handle)?;
+            Ok(sorted_spans(store, annotation)
+                .into_iter()
+                .filter(|(resource_handle, _, _)| *resource_handle == self.handle)
+                .map(|(_, begin, end)| (begin, end))
+                .collect())
+        })
+    }
+
     fn wrap_textselection(&self, textselection: TextSelection) -> PyTextSelection {
         PyTextSelection {
             textselection,
@@ -132,6 +889,14 @@ impl PyTextResource {
             store: self.store.clone(),
         }
     }
+
+    fn wrap_textselections<'py>(
+        &self,
+        textselections: Vec<TextSelection>,
+        py: Python<'py>,
+    ) -> &'py PyTuple {
+        wrap_textselections(textselections, self.handle, &self.store, py)
+    }
 }
 
 #[pyclass(name = "Cursor", frozen, freelist = 64)]
@@ -287,15 +1052,44 @@ impl PyTextSelection {
         })
     }
 
+    /// Text selections are hashed by resource and position only, so selections covering the same span are
+    /// equal and deduplicate in sets, regardless of whether they were obtained from the store's index or not
+    fn __hash__(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (
+            self.resource_handle,
+            self.textselection.begin(),
+            self.textselection.end(),
+        )
+            .hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns a stable hash (as a hexadecimal string) of the public ID of the resource and the position of this selection.
+    /// Unlike `hash()`, this is the same across runs and across stores, making it suitable as a cache key on disk.
+    fn content_hash(&self) -> PyResult<String> {
+        self.map(|resource| {
+            let content = format!(
+                "{}\0{}\0{}",
+                resource.id().unwrap_or_default(),
+                self.textselection.begin(),
+                self.textselection.end()
+            );
+            Ok(format!("{:016x}", fnv1a(content.as_bytes())))
+        })
+    }
+
     fn __richcmp__(&self, other: PyRef<Self>, op: CompareOp) -> Py<PyAny> {
         let py = other.py();
         match op {
             CompareOp::Eq => (self.resource_handle == other.resource_handle
-                && self.textselection == other.textselection)
-                .into_py(py),
+                && self.textselection.begin() == other.textselection.begin()
+                && self.textselection.end() == other.textselection.end())
+            .into_py(py),
             CompareOp::Ne => (self.resource_handle != other.resource_handle
-                || self.textselection != other.textselection)
-                .into_py(py),
+                || self.textselection.begin() != other.textselection.begin()
+                || self.textselection.end() != other.textselection.end())
+            .into_py(py),
             CompareOp::Lt => (self.textselection < other.textselection).into_py(py),
             CompareOp::Le => (self.textselection <= other.textselection).into_py(py),
             CompareOp::Gt => (self.textselection > other.textselection).into_py(py),
@@ -320,9 +1114,402 @@ impl PyTextSelection {
     fn end(&self) -> usize {
         self.textselection.end()
     }
+
+    /// Returns the tags on exactly this selection (as added with `AnnotationStore.tag()`), i.e. the values of the key
+    /// `tag` in the annotation set `set`, in a tuple of strings.
+    #[pyo3(signature = (set="tags"))]
+    fn tags<'py>(&self, set: &str, py: Python<'py>) -> PyResult<&'py PyTuple> {
+        let span = (
+            self.resource_handle,
+            self.textselection.begin(),
+            self.textselection.end(),
+        );
+        let tags = self.map_store(|store| {
+            let mut tags = Vec::new();
+            let (set, key) = match store.resolve_dataset_id(set).and_then(|sethandle| {
+                let annotationset: &AnnotationDataSet = store.get(sethandle)?;
+                Ok((sethandle, annotationset.resolve_key_id("tag")?))
+            }) {
+                Ok(found) => found,
+                Err(_) => return Ok(tags),
+            };
+            for handle in store
+                .annotations_by_resource(self.resource_handle)
+                .into_iter()
+                .flatten()
+            {
+                let annotation: &Annotation = store.get(handle)?;
+                if sorted_spans(store, annotation) == [span] {
+                    for (dataset, data) in resolve_data(store, annotation) {
+                        if dataset == set && data.key() == key {
+                            tags.push(data.value().to_string());
+                        }
+                    }
+                }
+            }
+            Ok(tags)
+        })?;
+        Ok(PyTuple::new(py, tags))
+    }
+
+    /// Returns the annotations with a text span that covers this selection entirely, in a tuple.
+    /// If a `key` is provided, only annotations with data for that key are returned, and if a `value` is provided as well,
+    /// the data must also have that value.
+    #[pyo3(signature = (key=None, value=None))]
+    fn covering_annotations<'py>(
+        &self,
+        key: Option<PyRef<PyDataKey>>,
+        value: Option<&PyAny>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let (begin, end) = (self.textselection.begin(), self.textselection.end());
+        self.related_annotations(key.as_deref(), value, py, |spans| {
+            spans.iter().any(|(reshandle, spanbegin, spanend)| {
+                *reshandle == self.resource_handle && *spanbegin <= begin && *spanend >= end
+            })
+        })
+    }
+
+    /// Returns the annotations whose text spans all lie within this selection, in a tuple.
+    /// If a `key` is provided, only annotations with data for that key are returned, and if a `value` is provided as well,
+    /// the data must also have that value.
+    #[pyo3(signature = (key=None, value=None))]
+    fn within_annotations<'py>(
+        &self,
+        key: Option<PyRef<PyDataKey>>,
+        value: Option<&PyAny>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let (begin, end) = (self.textselection.begin(), self.textselection.end());
+        self.related_annotations(key.as_deref(), value, py, |spans| {
+            !spans.is_empty()
+                && spans.iter().all(|(reshandle, spanbegin, spanend)| {
+                    *reshandle == self.resource_handle && *spanbegin >= begin && *spanend <= end
+                })
+        })
+    }
+
+    /// Returns the number of characters this selection shares with another one (0 if they are disjoint or in different resources)
+    /// This and the other methods that relate two selections raise a ValueError if the selections are from different stores.
+    fn overlap_length(&self, other: PyRef<Self>) -> PyResult<usize> {
+        self.overlap(&other)
+    }
+
+    /// Tests whether this selection overlaps with another one
+    fn overlaps(&self, other: PyRef<Self>) -> PyResult<bool> {
+        self.test_relation(&other, SpanRelation::Overlaps)
+    }
+
+    /// Tests whether this selection embeds another one entirely
+    fn embeds(&self, other: PyRef<Self>) -> PyResult<bool> {
+        self.test_relation(&other, SpanRelation::Embeds)
+    }
+
+    /// Tests whether this selection is entirely embedded in another one
+    fn embedded_in(&self, other: PyRef<Self>) -> PyResult<bool> {
+        self.test_relation(&other, SpanRelation::EmbeddedIn)
+    }
+
+    /// Tests whether this selection ends exactly where another one begins
+    fn precedes(&self, other: PyRef<Self>) -> PyResult<bool> {
+        self.test_relation(&other, SpanRelation::Precedes)
+    }
+
+    /// Tests whether this selection begins exactly where another one ends
+    fn succeeds(&self, other: PyRef<Self>) -> PyResult<bool> {
+        self.test_relation(&other, SpanRelation::Succeeds)
+    }
+
+    /// Tests whether this selection comes entirely before another one
+    fn before(&self, other: PyRef<Self>) -> PyResult<bool> {
+        self.test_relation(&other, SpanRelation::Before)
+    }
+
+    /// Tests whether this selection comes entirely after another one
+    fn after(&self, other: PyRef<Self>) -> PyResult<bool> {
+        self.test_relation(&other, SpanRelation::After)
+    }
+
+    /// Returns the part of the text this selection has in common with another one, as a TextSelection,
+    /// or None if the selections do not overlap (or are in different resources).
+    fn intersection(&self, other: PyRef<Self>) -> PyResult<Option<PyTextSelection>> {
+        if self.overlap(&other)? == 0 {
+            return Ok(None);
+        }
+        let begin = self.textselection.begin().max(other.textselection.begin());
+        let end = self.textselection.end().min(other.textselection.end());
+        self.map(|res| {
+            Ok(Some(PyTextSelection {
+                textselection: res.textselection(&Offset::simple(begin, end))?,
+                resource_handle: self.resource_handle,
+                store: self.store.clone(),
+            }))
+        })
+    }
+
+    /// Returns the intersection over union of this selection and another one, a score between 0.0 (disjoint) and 1.0 (identical)
+    fn iou(&self, other: PyRef<Self>) -> PyResult<f64> {
+        let intersection = self.overlap(&other)?;
+        if intersection == 0 {
+            return Ok(0.0);
+        }
+        let union = (self.textselection.end() - self.textselection.begin())
+            + (other.textselection.end() - other.textselection.begin())
+            - intersection;
+        Ok(intersection as f64 / union as f64)
+    }
+
+    /// Returns the begin and end of this selection in several offset conventions at once, as a dictionary with the keys
+    /// `char` (unicode points, as used by STAM), `utf8byte` (bytes in UTF-8) and `utf16` (UTF-16 code units, as used by
+    /// JavaScript), each mapping to a (begin, end) tuple. All offsets are relative to the resource.
+    fn offsets<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let (begin, end) = (self.textselection.begin(), self.textselection.end());
+        let (bytebegin, byteend, utf16begin, utf16end) = self.map(|resource| {
+            let text = resource.text();
+            let bytebegin = charpos_to_bytepos(text, begin);
+            let byteend = bytebegin + charpos_to_bytepos(&text[bytebegin..], end - begin);
+            let utf16begin = text[..bytebegin].encode_utf16().count();
+            let utf16end = utf16begin + text[bytebegin..byteend].encode_utf16().count();
+            Ok((bytebegin, byteend, utf16begin, utf16end))
+        })?;
+        let dict = PyDict::new(py);
+        dict.set_item("char", (begin, end))?;
+        dict.set_item("utf8byte", (bytebegin, byteend))?;
+        dict.set_item("utf16", (utf16begin, utf16end))?;
+        Ok(dict)
+    }
+
+    /// Converts a position in unicode points to the corresponding position in UTF-16 code units (as used by JavaScript).
+    /// Both positions are relative to this selection. Raises an IndexError if the position is beyond the end of the selection.
+    fn utf16unit(&self, cursor: usize) -> PyResult<usize> {
+        self.map(|res| {
+            Ok(charpos_to_utf16unit(
+                res.text_of(&(self.textselection.into()))?,
+                cursor,
+            ))
+        })?
+    }
+
+    /// Converts a position in UTF-16 code units (as used by JavaScript) to the corresponding position in unicode points.
+    /// Both positions are relative to this selection. Raises an IndexError if the position is beyond the end of the selection
+    /// or in the middle of a surrogate pair.
+    fn utf16unit_to_charpos(&self, unit: usize) -> PyResult<usize> {
+        self.map(|res| {
+            Ok(utf16unit_to_charpos(
+                res.text_of(&(self.textselection.into()))?,
+                unit,
+            ))
+        })?
+    }
+
+    /// Returns the position of this selection relative to its resource, as a (begin, end) tuple of floats between 0.0 and 1.0
+    fn relative_position(&self) -> PyResult<(f64, f64)> {
+        let textlen = self.map(|resource| Ok(resource.textlen()))?;
+        if textlen == 0 {
+            return Ok((0.0, 0.0));
+        }
+        Ok((
+            self.textselection.begin() as f64 / textlen as f64,
+            self.textselection.end() as f64 / textlen as f64,
+        ))
+    }
+
+    /// Splits the text of this selection on a regular expression, returns a tuple of TextSelections for the parts in between
+    /// the matches. Empty parts are omitted. If `limit` is set, at most that many parts are returned, the last of which
+    /// contains the unsplit remainder.
+    #[pyo3(signature = (pattern, limit=None))]
+    fn split_text_regex<'py>(
+        &self,
+        pattern: &str,
+        limit: Option<usize>,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let regex = compile_regex(pattern)?;
+        let textselections = self.map(|res| {
+            split_regex(
+                res,
+                self.textselection.begin(),
+                self.textselection.end(),
+                &regex,
+                limit,
+            )
+        })?;
+        Ok(wrap_textselections(
+            textselections,
+            self.resource_handle,
+            &self.store,
+            py,
+        ))
+    }
+
+    /// Segments the text of this selection into sentences, returns a tuple of TextSelections.
+    /// The default segmenter splits after sentence-final punctuation that is followed by whitespace.
+    /// A custom `segmenter` can be passed, a callable that takes a string and returns (begin, end) tuples (in unicode points).
+    /// A ValueError is raised if any of those is not within the string.
+    #[pyo3(signature = (segmenter=None))]
+    fn sentences<'py>(&self, segmenter: Option<&PyAny>, py: Python<'py>) -> PyResult<&'py PyTuple> {
+        segment(
+            &self.store,
+            self.resource_handle,
+            self.textselection.begin(),
+            self.textselection.end(),
+            Segmentation::Sentences,
+            segmenter,
+            py,
+        )
+    }
+
+    /// Segments the text of this selection into words, returns a tuple of TextSelections.
+    /// The default segmenter returns all consecutive runs of alphanumeric characters.
+    /// A custom `segmenter` can be passed, a callable that takes a string and returns (begin, end) tuples (in unicode points).
+    /// A ValueError is raised if any of those is not within the string.
+    #[pyo3(signature = (segmenter=None))]
+    fn words<'py>(&self, segmenter: Option<&PyAny>, py: Python<'py>) -> PyResult<&'py PyTuple> {
+        segment(
+            &self.store,
+            self.resource_handle,
+            self.textselection.begin(),
+            self.textselection.end(),
+            Segmentation::Words,
+            segmenter,
+            py,
+        )
+    }
+
+    /// Returns a new TextSelection that extends this one by the specified amount to the left and right,
+    /// clamped to the bounds of the resource. The unit is either "char" (the default) or "word", where words are delimited by whitespace.
+    #[pyo3(signature = (left=0, right=0, unit="char"))]
+    fn expand(&self, left: usize, right: usize, unit: &str) -> PyResult<PyTextSelection> {
+        let begin = self.textselection.begin();
+        let end = self.textselection.end();
+        let (begin, end) = match unit {
+            "char" => {
+                self.map(|res| Ok((begin.saturating_sub(left), (end + right).min(res.textlen()))))?
+            }
+            "word" => self.map(|res| {
+                let text = res.text();
+                let before = &text[..charpos_to_bytepos(text, begin)];
+                let after = &text[charpos_to_bytepos(text, end)..];
+                Ok((
+                    begin - count_words(before.chars().rev(), left),
+                    end + count_words(after.chars(), right),
+                ))
+            })?,
+            _ => {
+                return Err(PyValueError::new_err(
+                    "Unit must be either \"char\" or \"word\"",
+                ))
+            }
+        };
+        self.map(|res| {
+            Ok(PyTextSelection {
+                textselection: res.textselection(&Offset::simple(begin, end))?,
+                resource_handle: self.resource_handle,
+                store: self.store.clone(),
+            })
+        })
+    }
+
+    /// Returns the base direction of the text: "ltr", "rtl" or "mixed", based on the bidi classes of the
+    /// characters with a strong direction. Text without any strongly directional characters is considered "ltr".
+    fn base_direction(&self) -> PyResult<&'static str> {
+        self.map(|res| {
+            let text = res.text_of(&(self.textselection.into()))?;
+            let mut ltr = false;
+            let mut rtl = false;
+            for c in text.chars() {
+                match unicode_bidi::bidi_class(c) {
+                    BidiClass::L => ltr = true,
+                    BidiClass::R | BidiClass::AL => rtl = true,
+                    _ => {}
+                }
+            }
+            Ok(match (ltr, rtl) {
+                (true, true) => "mixed",
+                (false, true) => "rtl",
+                _ => "ltr",
+            })
+        })
+    }
 }
 
 impl PyTextSelection {
+    /// Returns the annotations on the resource of this selection whose (sorted) text spans pass the test, and that have the
+    /// specified data (if any)
+    fn related_annotations<'py, F>(
+        &self,
+        key: Option<&PyDataKey>,
+        value: Option<&PyAny>,
+        py: Python<'py>,
+        test: F,
+    ) -> PyResult<&'py PyTuple>
+    where
+        F: Fn(&[(TextResourceHandle, usize, usize)]) -> bool,
+    {
+        let value = value
+            .map(py_into_datavalue)
+            .transpose()
+            .map_err(|err| PyStamError::new_err(format!("{}", err)))?;
+        let handles = self.map_store(|store| {
+            let mut handles = Vec::new();
+            for handle in store
+                .annotations_by_resource(self.resource_handle)
+                .into_iter()
+                .flatten()
+            {
+                let annotation: &Annotation = store.get(handle)?;
+                if test(&sorted_spans(store, annotation))
+                    && has_data(store, annotation, key, value.as_ref())
+                {
+                    handles.push(handle);
+                }
+            }
+            Ok(handles)
+        })?;
+        let elements: Vec<Py<PyAnnotation>> = handles
+            .into_iter()
+            .map(|handle| {
+                Py::new(
+                    py,
+                    PyAnnotation {
+                        handle,
+                        store: self.store.clone(),
+                    },
+                )
+                .expect("wrapping PyAnnotation")
+            })
+            .collect();
+        Ok(PyTuple::new(py, elements))
+    }
+
+    /// Tests how this selection relates to another one
+    fn test_relation(&self, other: &PyTextSelection, relation: SpanRelation) -> PyResult<bool> {
+        self.check_same_store(other)?;
+        Ok(relation.test(
+            &[(
+                self.resource_handle,
+                self.textselection.begin(),
+                self.textselection.end(),
+            )],
+            &[(
+                other.resource_handle,
+                other.textselection.begin(),
+                other.textselection.end(),
+            )],
+        ))
+    }
+
+    /// Returns the number of characters shared with another text selection
+    pub(crate) fn overlap(&self, other: &PyTextSelection) -> PyResult<usize> {
+        self.check_same_store(other)?;
+        if self.resource_handle != other.resource_handle {
+            return Ok(0);
+        }
+        let begin = self.textselection.begin().max(other.textselection.begin());
+        let end = self.textselection.end().min(other.textselection.end());
+        Ok(end.saturating_sub(begin))
+    }
+
     fn map<T, F>(&self, f: F) -> Result<T, PyErr>
     where
         F: FnOnce(&TextResource) -> Result<T, StamError>,
@@ -340,6 +1527,303 @@ impl PyTextSelection {
     }
 }
 
+/// Converts byte positions to positions in unicode points.
+/// Efficient when the byte positions are requested in ascending order, as is the case for regular expression matches.
+pub(crate) struct CharPosConverter<'a> {
+    text: &'a str,
+    bytepos: usize,
+    charpos: usize,
+}
+
+impl<'a> CharPosConverter<'a> {
+    pub(crate) fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            bytepos: 0,
+            charpos: 0,
+        }
+    }
+
+    pub(crate) fn charpos(&mut self, bytepos: usize) -> usize {
+        if bytepos < self.bytepos {
+            //we can only count forward, start over
+            self.bytepos = 0;
+            self.charpos = 0;
+        }
+        self.charpos += self.text[self.bytepos..bytepos].chars().count();
+        self.bytepos = bytepos;
+        self.charpos
+    }
+}
+
+/// Lowercases a text and optionally strips diacritics. Returns the folded text, along with the position (in unicode points) in the
+/// original text for each character of the folded text.
+fn fold_text(text: &str, diacritics: bool) -> (String, Vec<usize>) {
+    let mut folded = String::with_capacity(text.len());
+    let mut origins = Vec::with_capacity(text.len());
+    for (charpos, c) in text.chars().enumerate() {
+        for c in c.to_lowercase() {
+            if diacritics {
+                let mut decomposed = String::new();
+                unicode_normalization::char::decompose_canonical(c, |c| decomposed.push(c));
+                for c in decomposed.chars() {
+                    if !unicode_normalization::char::is_combining_mark(c) {
+                        folded.push(c);
+                        origins.push(charpos);
+                    }
+                }
+            } else {
+                folded.push(c);
+                origins.push(charpos);
+            }
+        }
+    }
+    (folded, origins)
+}
+
+pub(crate) fn compile_regex(pattern: &str) -> PyResult<Regex> {
+    Regex::new(pattern)
+        .map_err(|err| PyValueError::new_err(format!("Invalid regular expression: {}", err)))
+}
+
+/// Splits the text between begin and end (in unicode points) on a regular expression
+fn split_regex(
+    res: &TextResource,
+    begin: usize,
+    end: usize,
+    regex: &Regex,
+    limit: Option<usize>,
+) -> Result<Vec<TextSelection>, StamError> {
+    let text = res.text_slice(&Offset::simple(begin, end))?;
+    let mut converter = CharPosConverter::new(text);
+    let mut parts: Vec<(usize, usize)> = Vec::new();
+    let mut partbegin = 0;
+    for m in regex.find_iter(text) {
+        if limit.map_or(false, |limit| parts.len() + 1 >= limit) {
+            break;
+        }
+        if m.start() > partbegin {
+            parts.push((converter.charpos(partbegin), converter.charpos(m.start())));
+        }
+        partbegin = m.end();
+    }
+    if text.len() > partbegin {
+        parts.push((converter.charpos(partbegin), converter.charpos(text.len())));
+    }
+    parts
+        .into_iter()
+        .map(|(partbegin, partend)| {
+            res.textselection(&Offset::simple(begin + partbegin, begin + partend))
+        })
+        .collect()
+}
+
+pub(crate) fn wrap_textselections<'py>(
+    textselections: Vec<TextSelection>,
+    resource_handle: TextResourceHandle,
+    store: &Arc<RwLock<AnnotationStore>>,
+    py: Python<'py>,
+) -> &'py PyTuple {
+    let elements: Vec<Py<PyTextSelection>> = textselections
+        .into_iter()
+        .map(|textselection| {
+            Py::new(
+                py,
+                PyTextSelection {
+                    textselection,
+                    resource_handle,
+                    store: store.clone(),
+                },
+            )
+            .expect("wrapping PyTextSelection")
+        })
+        .collect();
+    PyTuple::new(py, elements)
+}
+
+/// Map function to act on a resource, for when there is no PyTextResource or PyTextSelection instance to call map() on
+fn map_resource<T, F>(
+    store: &Arc<RwLock<AnnotationStore>>,
+    resource_handle: TextResourceHandle,
+    f: F,
+) -> Result<T, PyErr>
+where
+    F: FnOnce(&TextResource) -> Result<T, StamError>,
+{
+    if let Ok(store) = store.read() {
+        let resource: &TextResource = store
+            .resource(&resource_handle.into())
+            .ok_or_else(|| PyRuntimeError::new_err("Failed to resolve textresource"))?;
+        f(resource).map_err(|err| PyStamError::new_err(format!("{}", err)))
+    } else {
+        Err(PyRuntimeError::new_err(
+            "Unable to obtain store (should never happen)",
+        ))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Segmentation {
+    Sentences,
+    Words,
+}
+
+/// Segments the text between begin and end (in unicode points) in a resource, using either the default segmenter or a custom python callable
+fn segment<'py>(
+    store: &Arc<RwLock<AnnotationStore>>,
+    resource_handle: TextResourceHandle,
+    begin: usize,
+    end: usize,
+    segmentation: Segmentation,
+    segmenter: Option<&PyAny>,
+    py: Python<'py>,
+) -> PyResult<&'py PyTuple> {
+    let segments: Vec<(usize, usize)> = if let Some(segmenter) = segmenter {
+        //copy the text so no lock is held while calling back into python
+        let text: String = map_resource(store, resource_handle, |res| {
+            Ok(res.text_slice(&Offset::simple(begin, end))?.to_owned())
+        })?;
+        let segments: Vec<(usize, usize)> = segmenter.call1((text,))?.extract()?;
+        if let Some((segbegin, segend)) = segments
+            .iter()
+            .find(|(segbegin, segend)| segbegin > segend || *segend > end - begin)
+        {
+            return Err(PyValueError::new_err(format!(
+                "Segmenter returned invalid segment ({}, {}) for a text of length {}",
+                segbegin,
+                segend,
+                end - begin
+            )));
+        }
+        segments
+    } else {
+        map_resource(store, resource_handle, |res| {
+            Ok(default_segmenter(
+                res.text_slice(&Offset::simple(begin, end))?,
+                segmentation,
+            ))
+        })?
+    };
+    let textselections: Vec<TextSelection> = map_resource(store, resource_handle, |res| {
+        segments
+            .iter()
+            .map(|(segbegin, segend)| {
+                res.textselection(&Offset::simple(begin + segbegin, begin + segend))
+            })
+            .collect()
+    })?;
+    Ok(wrap_textselections(
+        textselections,
+        resource_handle,
+        store,
+        py,
+    ))
+}
+
+/// A simple unicode-aware segmenter, returns (begin, end) offsets in unicode points
+fn default_segmenter(text: &str, segmentation: Segmentation) -> Vec<(usize, usize)> {
+    let mut segments = Vec::new();
+    let mut chars = text.chars().enumerate().peekable();
+    match segmentation {
+        Segmentation::Words => {
+            let mut wordbegin: Option<usize> = None;
+            for (i, c) in chars {
+                if c.is_alphanumeric() {
+                    wordbegin.get_or_insert(i);
+                } else if let Some(begin) = wordbegin.take() {
+                    segments.push((begin, i));
+                }
+            }
+            if let Some(begin) = wordbegin {
+                segments.push((begin, text.chars().count()));
+            }
+        }
+        Segmentation::Sentences => {
+            let mut sentencebegin: Option<usize> = None;
+            let mut lastnonspace = 0;
+            while let Some((i, c)) = chars.next() {
+                if c.is_whitespace() {
+                    continue;
+                }
+                sentencebegin.get_or_insert(i);
+                lastnonspace = i + 1;
+                if matches!(c, '.' | '!' | '?' | '…' | '。' | '！' | '？')
+                    && chars.peek().map_or(true, |(_, next)| next.is_whitespace())
+                {
+                    if let Some(begin) = sentencebegin.take() {
+                        segments.push((begin, lastnonspace));
+                    }
+                }
+            }
+            if let Some(begin) = sentencebegin {
+                segments.push((begin, lastnonspace));
+            }
+        }
+    }
+    segments
+}
+
+/// Converts a position in unicode points to a byte position in the text. Positions beyond the end are mapped to the end.
+pub(crate) fn charpos_to_bytepos(text: &str, charpos: usize) -> usize {
+    text.char_indices()
+        .nth(charpos)
+        .map(|(bytepos, _)| bytepos)
+        .unwrap_or(text.len())
+}
+
+/// Converts a position in unicode points to a position in UTF-16 code units
+fn charpos_to_utf16unit(text: &str, charpos: usize) -> PyResult<usize> {
+    let mut chars = text.chars();
+    let mut unit = 0;
+    for _ in 0..charpos {
+        let c = chars
+            .next()
+            .ok_or_else(|| PyIndexError::new_err("Position out of bounds"))?;
+        unit += c.len_utf16();
+    }
+    Ok(unit)
+}
+
+/// Converts a position in UTF-16 code units to a position in unicode points
+fn utf16unit_to_charpos(text: &str, unit: usize) -> PyResult<usize> {
+    let mut units = 0;
+    for (charpos, c) in text.chars().enumerate() {
+        if units == unit {
+            return Ok(charpos);
+        } else if units > unit {
+            return Err(PyIndexError::new_err(
+                "Position is in the middle of a surrogate pair",
+            ));
+        }
+        units += c.len_utf16();
+    }
+    if units == unit {
+        Ok(text.chars().count())
+    } else if units > unit {
+        Err(PyIndexError::new_err(
+            "Position is in the middle of a surrogate pair",
+        ))
+    } else {
+        Err(PyIndexError::new_err("Position out of bounds"))
+    }
+}
+
+/// Walks over the specified number of whitespace-delimited words (and any whitespace preceding them),
+/// returns the number of characters passed.
+fn count_words(chars: impl Iterator<Item = char>, words: usize) -> usize {
+    let mut chars = chars.peekable();
+    let mut count = 0;
+    for _ in 0..words {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {
+            count += 1;
+        }
+        while chars.next_if(|c| !c.is_whitespace()).is_some() {
+            count += 1;
+        }
+    }
+    count
+}
+
 impl From<PyTextSelection> for TextSelection {
     fn from(other: PyTextSelection) -> Self {
         other.textselection
@@ -348,12 +1832,13 @@ impl From<PyTextSelection> for TextSelection {
 
 #[pyclass(name = "TextSelectionIter")]
 // this isn't based off the TextSelectionIter in the Rust library because that one holds a
-// reference which we can't in Python. This one will have somewhat more overhead and only supports
-// forward iteration
+// reference which we can't in Python. This one will have somewhat more overhead. Reverse iteration
+// is supported by passing the positions in reverse order and setting the reverse flag.
 pub(crate) struct PyTextSelectionIter {
     pub(crate) positions: Vec<usize>,
     pub(crate) index: usize,
     pub(crate) subindex: usize,
+    pub(crate) reverse: bool,
     pub(crate) resource_handle: TextResourceHandle,
     pub(crate) store: Arc<RwLock<AnnotationStore>>,
 }
@@ -367,6 +1852,91 @@ impl PyTextSelectionIter {
     fn __next__(&mut self) -> Option<PyTextSelection> {
         self.next()
     }
+
+    /// Consumes the remaining textselections and returns them as a plain list, decoupled from the live state of the store.
+    /// This is the recommended pattern if you want to modify the store while processing them.
+    fn snapshot<'py>(&mut self, py: Python<'py>) -> &'py PyList {
+        let elements: Vec<Py<PyTextSelection>> = self
+            .by_ref()
+            .map(|textselection| Py::new(py, textselection).expect("wrapping PyTextSelection"))
+            .collect();
+        PyList::new(py, elements)
+    }
+
+    /// Consumes the remaining textselections and returns sliding windows over them, as a list of tuples of `size` consecutive
+    /// textselections. Consecutive windows start `step` textselections apart. Useful for n-grams over tokens.
+    #[pyo3(signature = (size, step=1))]
+    fn windows<'py>(&mut self, size: usize, step: usize, py: Python<'py>) -> PyResult<&'py PyList> {
+        if size == 0 || step == 0 {
+            return Err(PyValueError::new_err(
+                "size and step must be greater than zero",
+            ));
+        }
+        let textselections: Vec<PyTextSelection> = self.by_ref().collect();
+        let result = PyList::empty(py);
+        for window in textselections.windows(size).step_by(step) {
+            let elements: Vec<Py<PyTextSelection>> = window
+                .iter()
+                .map(|textselection| {
+                    Py::new(py, textselection.clone()).expect("wrapping PyTextSelection")
+                })
+                .collect();
+            result.append(PyTuple::new(py, elements))?;
+        }
+        Ok(result)
+    }
+
+    /// Consumes the remaining textselections and merges consecutive ones into single spanning textselections, returns a tuple.
+    /// Textselections are merged if they overlap or if the gap between them is at most `max_gap` characters.
+    /// If `allow_whitespace` is set, textselections separated by only whitespace are merged regardless of the size of the gap,
+    /// which is what is needed to reassemble tokens into phrases.
+    #[pyo3(signature = (max_gap=0, allow_whitespace=true))]
+    fn merge_adjacent<'py>(
+        &mut self,
+        max_gap: usize,
+        allow_whitespace: bool,
+        py: Python<'py>,
+    ) -> PyResult<&'py PyTuple> {
+        let mut spans: Vec<(usize, usize)> = self
+            .by_ref()
+            .map(|textselection| {
+                (
+                    textselection.textselection.begin(),
+                    textselection.textselection.end(),
+                )
+            })
+            .collect();
+        spans.sort_unstable();
+        let textselections = map_resource(&self.store, self.resource_handle, |res| {
+            let mut merged: Vec<(usize, usize)> = Vec::new();
+            for (begin, end) in spans {
+                if let Some(last) = merged.last_mut() {
+                    let mergeable = begin <= last.1
+                        || begin - last.1 <= max_gap
+                        || (allow_whitespace
+                            && res
+                                .text_slice(&Offset::simple(last.1, begin))?
+                                .chars()
+                                .all(char::is_whitespace));
+                    if mergeable {
+                        last.1 = last.1.max(end);
+                        continue;
+                    }
+                }
+                merged.push((begin, end));
+            }
+            merged
+                .into_iter()
+                .map(|(begin, end)| res.textselection(&Offset::simple(begin, end)))
+                .collect::<Result<Vec<_>, StamError>>()
+        })?;
+        Ok(wrap_textselections(
+            textselections,
+            self.resource_handle,
+            &self.store,
+            py,
+        ))
+    }
 }
 
 impl Iterator for PyTextSelectionIter {
@@ -378,8 +1948,16 @@ impl Iterator for PyTextSelectionIter {
                 loop {
                     if let Some(position) = self.positions.get(self.index) {
                         if let Some(positionitem) = resource.position(*position) {
+                            let nth = if self.reverse {
+                                positionitem
+                                    .iter_begin2end()
+                                    .count()
+                                    .checked_sub(self.subindex + 1)
+                            } else {
+                                Some(self.subindex)
+                            };
                             if let Some((_, handle)) =
-                                positionitem.iter_begin2end().nth(self.subindex)
+                                nth.and_then(|nth| positionitem.iter_begin2end().nth(nth))
                             {
                                 self.subindex += 1;
                                 let textselection: Result<&TextSelection, _> =
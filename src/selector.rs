@@ -7,8 +7,9 @@ use std::sync::{Arc, RwLock};
 
 use crate::annotation::PyAnnotation;
 use crate::annotationdataset::PyAnnotationDataSet;
+use crate::annotationstore::MapStore;
 use crate::error::PyStamError;
-use crate::resources::{PyOffset, PyTextResource};
+use crate::resources::{PyOffset, PyTextResource, PyTextSelection};
 use stam::*;
 
 #[pyclass(name = "SelectorKind")]
@@ -70,6 +71,46 @@ impl From<Selector> for PySelector {
     }
 }
 
+/// Converts a Python object that can serve as the target of an annotation into a Selector.
+/// Accepts a Selector, a TextSelection, an Annotation, or a (TextResource, Offset) tuple.
+/// A list of any of these is turned into a MultiSelector.
+pub(crate) fn py_into_selector(target: &PyAny) -> PyResult<Selector> {
+    if let Ok(selector) = target.extract::<PyRef<PySelector>>() {
+        Ok(selector.selector.clone())
+    } else if let Ok(true) = target.is_instance_of::<PyList>() {
+        let targets: &PyList = target.downcast().unwrap();
+        if targets.is_empty() {
+            return Err(PyValueError::new_err("Target list may not be empty"));
+        }
+        let mut subselectors: Vec<Selector> = Vec::with_capacity(targets.len());
+        for subtarget in targets {
+            subselectors.push(py_into_selector(subtarget)?);
+        }
+        Ok(Selector::MultiSelector(subselectors))
+    } else if let Ok(textselection) = target.extract::<PyRef<PyTextSelection>>() {
+        Ok(Selector::TextSelector(
+            textselection.resource_handle,
+            Offset::simple(
+                textselection.textselection.begin(),
+                textselection.textselection.end(),
+            ),
+        ))
+    } else if let Ok(annotation) = target.extract::<PyRef<PyAnnotation>>() {
+        Ok(Selector::AnnotationSelector(annotation.handle, None))
+    } else if let Ok((resource, offset)) =
+        target.extract::<(PyRef<PyTextResource>, PyRef<PyOffset>)>()
+    {
+        Ok(Selector::TextSelector(
+            resource.handle,
+            offset.offset.clone(),
+        ))
+    } else {
+        Err(PyValueError::new_err(
+            "Target must be a Selector, TextSelection, Annotation or a (TextResource, Offset) tuple",
+        ))
+    }
+}
+
 #[pymethods]
 impl PySelector {
     #[new]
@@ -199,6 +240,38 @@ impl PySelector {
         )
     }
 
+    #[staticmethod]
+    /// Shortcut static method to construct a TextSelector from UTF-8 byte offsets (as emitted by many external tools),
+    /// which are converted to offsets in unicode points. Raises a ValueError if an offset is out of bounds or does not fall
+    /// on a character boundary.
+    fn textselector_bytes(
+        resource: PyRef<PyTextResource>,
+        begin_byte: usize,
+        end_byte: usize,
+    ) -> PyResult<Self> {
+        let offset = resource.map_store(|store| {
+            let res: &TextResource = store.get(resource.handle)?;
+            let text = res.text();
+            if begin_byte > end_byte
+                || !text.is_char_boundary(begin_byte)
+                || !text.is_char_boundary(end_byte)
+            {
+                return Ok(None);
+            }
+            let begin = text[..begin_byte].chars().count();
+            let end = begin + text[begin_byte..end_byte].chars().count();
+            Ok(Some(Offset::simple(begin, end)))
+        })?;
+        let offset = offset.ok_or_else(|| {
+            PyValueError::new_err(
+                "Byte offsets must be in bounds, in order, and on character boundaries",
+            )
+        })?;
+        Ok(PySelector {
+            selector: Selector::TextSelector(resource.handle, offset),
+        })
+    }
+
     #[staticmethod]
     /// Shortcut static method to construct a AnnotationSelector
     fn annotation(annotation: PyRef<PyAnnotation>, offset: PyRef<PyOffset>) -> PyResult<Self> {
@@ -347,6 +420,13 @@ impl PySelector {
         }
     }
 
+    /// Visits this selector and all its subselectors (depth-first), calling `callback(selector, kind, depth, offset)`
+    /// for each of them. The `depth` is 0 for this selector, and `offset` is the Offset of text and annotation selectors
+    /// (or None). If the callback returns False, the subselectors of that selector are not visited.
+    fn walk(&self, callback: &PyAny, py: Python) -> PyResult<()> {
+        walk_selector(&self.selector, 0, callback, py)
+    }
+
     fn __richcmp__(&self, other: PyRef<Self>, op: CompareOp) -> Py<PyAny> {
         let py = other.py();
         match op {
@@ -356,3 +436,44 @@ impl PySelector {
         }
     }
 }
+
+/// Calls the callback for a selector and (unless it returns False) recursively for its subselectors
+fn walk_selector(selector: &Selector, depth: usize, callback: &PyAny, py: Python) -> PyResult<()> {
+    let offset = match selector {
+        Selector::TextSelector(_, offset) | Selector::AnnotationSelector(_, Some(offset)) => {
+            Some(Py::new(
+                py,
+                PyOffset {
+                    offset: offset.clone(),
+                },
+            )?)
+        }
+        _ => None,
+    };
+    let result = callback.call1((
+        PySelector {
+            selector: selector.clone(),
+        }
+        .into_py(py),
+        PySelectorKind {
+            kind: selector.kind(),
+        }
+        .into_py(py),
+        depth,
+        offset,
+    ))?;
+    if result.extract::<bool>().ok() == Some(false) {
+        return Ok(());
+    }
+    match selector {
+        Selector::MultiSelector(v)
+        | Selector::CompositeSelector(v)
+        | Selector::DirectionalSelector(v) => {
+            for subselector in v.iter() {
+                walk_selector(subselector, depth + 1, callback, py)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
@@ -2,10 +2,11 @@
 
 from os import environ
 import os.path
+import json
 import unittest
 
 #    v-- a single stam should work just as well but for some reason my linter (pyright) stumbles over it
-from stam.stam import AnnotationStore, Offset, AnnotationData, AnnotationDataBuilder, Selector, TextResource, DataKey, DataValue, AnnotationDataSet, Annotation, StamError, TextSelection, Cursor
+from stam.stam import AnnotationStore, Offset, AnnotationData, AnnotationDataBuilder, AnnotationBuilder, Selector, TextResource, DataKey, DataValue, AnnotationDataSet, Annotation, StamError, TextSelection, Cursor
 
 
 class Test0(unittest.TestCase):
@@ -37,6 +38,71 @@ class Test0(unittest.TestCase):
         offset2 = Offset.whole() #shortcut
         self.assertEqual( offset, offset2)
 
+    def test_datavalue_numpy(self):
+        try:
+            import numpy
+        except ImportError:
+            self.skipTest("numpy not available")
+        self.assertEqual(DataValue(numpy.int64(42)), DataValue(42))
+        self.assertEqual(DataValue(numpy.float32(0.5)), DataValue(0.5))
+        self.assertEqual(DataValue(numpy.bool_(True)), DataValue(True))
+
+    def test_datavalue_coercion(self):
+        self.assertEqual(DataValue("42").as_int(), 42)
+        self.assertEqual(DataValue(42).as_float(), 42.0)
+        self.assertEqual(DataValue("0.5").as_float(), 0.5)
+        self.assertEqual(DataValue(42).as_str(), "42")
+        self.assertEqual(DataValue("True").as_bool(), True)
+        self.assertEqual(DataValue("noun").as_int(default=0), 0)
+        with self.assertRaises(ValueError):
+            DataValue("noun").as_int()
+
+    def test_datavalue_types(self):
+        self.assertTrue(DataValue(42).is_numeric())
+        self.assertTrue(DataValue(0.5).is_numeric())
+        self.assertFalse(DataValue("42").is_numeric())
+        self.assertFalse(DataValue(True).is_numeric())
+        self.assertTrue(DataValue("42").is_string())
+        self.assertTrue(DataValue([1, 2]).is_list())
+        self.assertFalse(DataValue("42").is_list())
+        self.assertEqual(DataValue(3.0).as_int(), 3)
+        self.assertEqual(DataValue(1).as_bool(), True)
+        self.assertEqual(DataValue(False).as_str(), "false")
+        with self.assertRaises(ValueError):
+            DataValue(3.5).as_int()
+        with self.assertRaises(ValueError):
+            DataValue(1e30).as_int()
+        with self.assertRaises(ValueError):
+            DataValue([1, 2]).as_float()
+
+    def test_datavalue_ordering(self):
+        self.assertLess(DataValue(1), DataValue(2))
+        self.assertLess(DataValue(1), DataValue(1.5))
+        self.assertGreaterEqual(DataValue(2.0), DataValue(2))
+        self.assertLess(DataValue("apple"), DataValue("banana"))
+        self.assertEqual(sorted([DataValue(3), DataValue(0.5), DataValue(2)]), [DataValue(0.5), DataValue(2), DataValue(3)])
+        with self.assertRaises(TypeError):
+            DataValue(True) < DataValue([1, 2])
+        with self.assertRaises(TypeError):
+            DataValue("1") < DataValue(1)
+
+    def test_datavalue_hash(self):
+        self.assertEqual(hash(DataValue("noun")), hash(DataValue("noun")))
+        counts = { DataValue("noun"): 1 }
+        counts[DataValue("noun")] += 1
+        counts[DataValue("verb")] = 1
+        self.assertEqual(counts, { DataValue("noun"): 2, DataValue("verb"): 1 })
+        #equality is consistent with ordering and hashing
+        self.assertEqual(DataValue(0.0), DataValue(-0.0))
+        self.assertEqual(hash(DataValue(0.0)), hash(DataValue(-0.0)))
+        self.assertEqual(DataValue(1), DataValue(1.0))
+        self.assertEqual(hash(DataValue(1)), hash(DataValue(1.0)))
+        self.assertEqual({ DataValue(1): "one" }[DataValue(1.0)], "one")
+        a, b = DataValue(2), DataValue(2.0)
+        self.assertTrue(a <= b and a >= b and a == b and not a != b)
+        self.assertNotEqual(DataValue(1), DataValue("1"))
+        self.assertNotEqual(DataValue(9007199254740993), DataValue(9007199254740992.0))
+
 class Test1(unittest.TestCase):
     def setUp(self):
         """Create some data from scratch"""
@@ -100,6 +166,15 @@ class Test1(unittest.TestCase):
             self.assertEqual(str(annotationdata.value()), "noun") #force a string
         self.assertEqual(count,1)
 
+    def test_iter_data_nested(self):
+        """Nested iteration over the same object yields independent iterators"""
+        annotation = self.store.annotation("A1")
+        pairs = [ (a, b) for a in annotation for b in annotation ]
+        self.assertEqual(len(pairs), 1)
+        annotationset = self.store.annotationset("testdataset")
+        pairs = [ (a, b) for a in annotationset for b in annotationset ]
+        self.assertEqual(len(pairs), 1)
+
     def test_resource_text(self):
         """Get the text of an entire resource"""
         resource = self.store.resource("testres")
@@ -140,6 +215,11 @@ class Test1(unittest.TestCase):
             self.assertEqual(textselection.resource(), self.store.resource("testres"))
         self.assertEqual(count,1)
 
+    def test_annotation_resources(self):
+        annotation = self.store.annotation("A1")
+        self.assertEqual(annotation.resources(), (self.store.resource("testres"),))
+        self.assertEqual(annotation.resources_len(), 1)
+
     def test_annotationset_iter(self):
         """Iterate over all data in an annotationset"""
         annotationset = self.store.annotationset("testdataset")
@@ -225,6 +305,22 @@ class Test1(unittest.TestCase):
         annotationdata = annotationset.find_data("non-existent","non-existent")
         self.assertEqual(annotationdata, None)
 
+    def test_annotate_existing_data(self):
+        resource = self.store.resource("testres")
+        annotation = self.store.annotate(id="A2", target=(resource, Offset.simple(0,5)), data=["D1"])
+        data = self.store.annotationset("testdataset").annotationdata("D1")
+        self.assertEqual(list(annotation), [data])
+        annotation = self.store.annotate(id="A3", target=(resource, Offset.simple(0,5)), data=[data])
+        self.assertEqual(list(annotation), [data])
+        self.assertEqual(len(data.annotations()), 3)
+        with self.assertRaises(StamError):
+            self.store.annotate(id="A4", target=(resource, Offset.simple(0,5)), data=["non-existent-id"])
+
+    def test_ids(self):
+        self.assertEqual(self.store.annotation_ids(), {"A1"})
+        self.assertEqual(self.store.resource_ids(), {"testres"})
+        self.assertEqual(self.store.annotationset_ids(), {"testdataset"})
+
 
 
             
@@ -264,12 +360,88 @@ class Test2(unittest.TestCase):
         self.assertIsInstance( data, AnnotationData)
         self.assertTrue(data.has_id("D1"))
 
+    def test_annotate_generated_ids(self):
+        resource = self.store.resource("testres")
+        annotation = self.store.annotate(target=(resource, Offset.simple(0,5)),
+                            data=[AnnotationDataBuilder(key="pos", value="interjection", annotationset="testdataset")],
+                            id_prefix="ann-")
+        self.assertEqual(annotation.id, "ann-1")
+        annotation = self.store.annotate(target=(resource, Offset.simple(0,5)),
+                            data=[AnnotationDataBuilder(key="pos", value="interjection", annotationset="testdataset")],
+                            id_formatter=lambda i: f"ann-{i:04}")
+        self.assertEqual(annotation.id, "ann-0002")
+
+    def test_add_webannotation(self):
+        annotation = self.store.add_webannotation({
+            "@context": "http://www.w3.org/ns/anno.jsonld",
+            "id": "W1",
+            "type": "Annotation",
+            "body": { "type": "Dataset", "pos": "interjection" },
+            "target": {
+                "source": "https://example.org/testres",
+                "selector": { "type": "TextPositionSelector", "start": 0, "end": 5 }
+            }
+        })
+        self.assertEqual(annotation.id, "W1")
+        self.assertEqual(str(annotation), "Hello")
+        data = list(annotation)
+        self.assertEqual(len(data), 1)
+        self.assertEqual(str(data[0].key()), "pos")
+        self.assertEqual(data[0].value().get(), "interjection")
+        annotation = self.store.add_webannotation('{"body": {"note": "x"}, "target": "testres#char=6,11"}')
+        self.assertEqual(str(annotation), "world")
+
     def test_serialisation_file(self):
         TMPDIR = environ.get('TMPDIR', "/tmp")
         filename = os.path.join(TMPDIR, "testoutput.stam.json")
         #doesn't test the actual output!
         self.store.to_file(filename)
 
+    def test_add_resource_encoding(self):
+        TMPDIR = environ.get('TMPDIR', "/tmp")
+        filename = os.path.join(TMPDIR, "latin1.txt")
+        with open(filename, 'w',encoding='latin-1') as f:
+            f.write("Café")
+        resource = self.store.add_resource(filename=filename, encoding="latin-1")
+        self.assertEqual(str(resource), "Café")
+        self.assertEqual(resource.encoding(), "utf-8")
+
+    def test_add_resource_filename(self):
+        TMPDIR = environ.get('TMPDIR', "/tmp")
+        filename = os.path.join(TMPDIR, "standoff.txt")
+        with open(filename, 'w',encoding='utf-8') as f:
+            f.write("From file")
+        resource = self.store.add_resource(filename=filename, id="fromfile")
+        self.assertEqual(str(resource), "From file")
+
+    def test_add_resource_text_and_filename(self):
+        TMPDIR = environ.get('TMPDIR', "/tmp")
+        filename = os.path.join(TMPDIR, "standoff-new.txt")
+        if os.path.exists(filename):
+            os.unlink(filename)
+        resource = self.store.add_resource(filename=filename, text="In memory", id="inmemory")
+        self.assertEqual(str(resource), "In memory")
+        self.assertFalse(os.path.exists(filename))
+
+    def test_serialisation_indent(self):
+        minified = self.store.to_string(indent=0)
+        self.assertNotIn("\n", minified)
+        pretty = self.store.to_string(indent=4)
+        self.assertIn("\n    \"", pretty)
+        self.assertEqual(json.loads(minified), json.loads(pretty))
+
+    def test_serialisation_inline_resources(self):
+        TMPDIR = environ.get('TMPDIR', "/tmp")
+        filename = os.path.join(TMPDIR, "standoff-inline.txt")
+        self.store.add_resource(filename=filename, text="Stand-off text", id="standoff")
+        data = json.loads(self.store.to_string(inline_resources=True))
+        resources = { resource["@id"]: resource for resource in data["resources"] }
+        self.assertEqual(resources["standoff"]["text"], "Stand-off text")
+        self.assertNotIn("@include", resources["standoff"])
+        self.assertEqual(resources["testres"]["text"], "Hello world")
+        #formatting is retained
+        self.assertEqual(self.store.to_string(inline_resources=True).count("\n"), self.store.to_string().count("\n"))
+
     def test_serialisation_string(self):
         self.assertTrue(self.store.to_string()) #doesn't test the actual output!
  
@@ -364,6 +536,29 @@ class Test3b(unittest.TestCase):
         #test all sanity
         common_sanity(self)
 
+    def test_canonical_serialisation(self):
+        self.store = AnnotationStore(string=EXAMPLE3JSON)
+        json = self.store.to_string(canonical=True)
+        self.assertEqual(AnnotationStore(string=json).to_string(canonical=True), json)
+
+    def test_canonical_serialisation_sorted_keys(self):
+        self.store = AnnotationStore(string=EXAMPLE3JSON)
+        def check_sorted(pairs):
+            keys = [ key for key, _ in pairs ]
+            self.assertEqual(keys, sorted(keys))
+            return dict(pairs)
+        json.loads(self.store.to_string(canonical=True), object_pairs_hook=check_sorted)
+        json.loads(self.store.to_string(indent=2), object_pairs_hook=check_sorted)
+
+    def test_content_hash(self):
+        store1 = AnnotationStore(string=EXAMPLE3JSON)
+        store2 = AnnotationStore(string=EXAMPLE3JSON)
+        textselection1 = store1.annotation("A1").textselections()[0]
+        textselection2 = store2.annotation("A1").textselections()[0]
+        self.assertEqual(textselection1.content_hash(), textselection2.content_hash())
+        self.assertEqual(textselection1.content_hash(), "149112f86ac1a1a7")
+        self.assertNotEqual(textselection1.content_hash(), store1.resource("testres").textselection(Offset.simple(0,5)).content_hash())
+
 class Test4(unittest.TestCase):
     def setUp(self):
         """Create some data from scratch"""
@@ -390,9 +585,760 @@ class Test4(unittest.TestCase):
         self.assertEqual(str(textselections[0]), "Hello")
         self.assertEqual(str(textselections[1]), "world")
 
+    def test_textselections_reverse(self):
+        resource = self.store.resource("testres")
+        textselections = list(resource.range(0, 11, reverse=True))
+        self.assertEqual([ str(t) for t in textselections ], ["world", "Hello"])
+
+    def test_textselections_windows(self):
+        resource = self.store.resource("testres")
+        windows = resource.textselections().windows(2)
+        self.assertEqual(len(windows), 1)
+        self.assertEqual([ str(t) for t in windows[0] ], ["Hello", "world"])
+        self.assertEqual(resource.textselections().windows(3), [])
+
+    def test_shares_data(self):
+        resource = self.store.resource("testres")
+        data = self.store.annotationset("testdataset").annotationdata("D1")
+        annotation = self.store.annotate(id="A3", target=(resource, Offset.simple(0,5)), data=[AnnotationDataBuilder.link(data)])
+        self.assertTrue(annotation.shares_data(self.store.annotation("A1")))
+        self.assertFalse(annotation.shares_data(self.store.annotation("A2")))
+        self.assertEqual(annotation.common_data(self.store.annotation("A1")), (data,))
+        copy = AnnotationStore(string=self.store.to_string())
+        with self.assertRaises(ValueError):
+            annotation.shares_data(copy.annotation("A1"))
+
     def test_test_multiselector(self):
         pass #TODO
 
+    def test_annotate_multiple_targets(self):
+        annotation = self.store.annotate(id="Word2",
+                            target=[ Selector.annotation(self.store.annotation("A1"), Offset.whole()), self.store.annotation("A2") ],
+                            data=[AnnotationDataBuilder(key="type", value="word", annotationset="testdataset")])
+        self.assertEqual(len(annotation.annotations()), 2)
+        self.assertEqual(sorted(annotation.text()), ["Hello", "world"])
+
+    def test_annotation_textlen(self):
+        self.assertEqual(self.store.annotation("A1").textlen(), 5)
+        self.assertEqual(self.store.annotation("Word").textlen(), 10)
+
+    def test_discontinuous(self):
+        annotation = self.store.annotation("Word")
+        self.assertTrue(annotation.is_discontinuous())
+        self.assertEqual([ str(gap) for gap in annotation.gaps() ], [" "])
+        self.assertFalse(self.store.annotation("A1").is_discontinuous())
+        self.assertEqual(self.store.annotation("A1").gaps(), ())
+
+    def test_annotation_builder(self):
+        resource = self.store.resource("testres")
+        template = AnnotationBuilder().target((resource, Offset.simple(0,5))).add_data(AnnotationDataBuilder(key="type", value="word", annotationset="testdataset"))
+        annotation = self.store.annotate(template, id="Built1")
+        self.assertEqual(annotation.id, "Built1")
+        self.assertEqual(str(annotation), "Hello")
+        annotation = self.store.annotate(AnnotationBuilder().id("Built2").target((resource, Offset.simple(6,11))).target(self.store.annotation("A2")).add_data(AnnotationDataBuilder(key="type", value="word", annotationset="testdataset")))
+        self.assertEqual(annotation.id, "Built2")
+        self.assertEqual(sorted(annotation.text()), ["Hello", "world"])
+        with self.assertRaises(ValueError):
+            self.store.annotate(AnnotationBuilder(), id="Built3")
+
+    def test_textselections_textual_order(self):
+        resource = self.store.resource("testres")
+        annotation = self.store.annotate(id="Reversed", target=[(resource, Offset.simple(6,11)), (resource, Offset.simple(0,5))], data=[AnnotationDataBuilder(key="type", value="word", annotationset="testdataset")])
+        self.assertEqual([ str(ts) for ts in annotation.textselections() ], ["world", "Hello"])
+        self.assertEqual([ str(ts) for ts in annotation.textselections(textual_order=True) ], ["Hello", "world"])
+
+    def test_text_hash(self):
+        resource = self.store.resource("testres")
+        hash = self.store.annotation("Word").text_hash()
+        self.assertEqual(len(hash), 16)
+        reversed = self.store.annotate(id="Reversed2", target=[(resource, Offset.simple(6,11)), (resource, Offset.simple(0,5))], data=[AnnotationDataBuilder(key="type", value="word", annotationset="testdataset")])
+        self.assertEqual(reversed.text_hash(), hash)
+        self.assertNotEqual(self.store.annotation("A1").text_hash(), hash)
+
+    def test_target_spans(self):
+        spans = self.store.annotation("Word").target_spans()
+        self.assertEqual(len(spans), 2)
+        for resource, offset in spans:
+            self.assertEqual(resource, self.store.resource("testres"))
+        self.assertEqual(sorted( (offset.begin().value(), offset.end().value()) for _, offset in spans), [(0,5),(6,11)])
+
+    def test_textselection_expand(self):
+        resource = self.store.resource("testres")
+        textselection = resource.textselection(Offset.simple(6,8))
+        self.assertEqual(str(textselection.expand(left=2, right=2)), "o wor")
+        self.assertEqual(str(textselection.expand(left=99, right=99)), "Hello world")
+        self.assertEqual(str(textselection.expand(left=1, unit="word")), "Hello wo")
+
+    def test_textselection_relative_position(self):
+        resource = self.store.resource("testres")
+        self.assertEqual(resource.textselection(Offset.simple(0,11)).relative_position(), (0.0, 1.0))
+        begin, end = resource.textselection(Offset.simple(6,11)).relative_position()
+        self.assertAlmostEqual(begin, 6/11)
+        self.assertEqual(end, 1.0)
+
+    def test_textselection_overlap(self):
+        resource = self.store.resource("testres")
+        textselection = resource.textselection(Offset.simple(0,8))
+        other = resource.textselection(Offset.simple(4,10))
+        self.assertEqual(textselection.overlap_length(other), 4)
+        self.assertEqual(textselection.iou(other), 0.4)
+        self.assertEqual(textselection.overlap_length(resource.textselection(Offset.simple(8,11))), 0)
+        self.assertEqual(textselection.iou(textselection), 1.0)
+
+    def test_selections_covering(self):
+        resource = self.store.resource("testres")
+        covering = resource.selections_covering([7, 0, 5])
+        self.assertEqual([ str(x) for x in covering[0] ], ["world"])
+        self.assertEqual([ str(x) for x in covering[1] ], ["Hello"])
+        self.assertEqual(covering[2], ())
+
+    def test_textselection_hash(self):
+        resource = self.store.resource("testres")
+        selections = { resource.textselection(Offset.simple(0,5)), self.store.annotation("A2").textselections()[0] }
+        selections.update(resource.find_text_regex("Hello"))
+        self.assertEqual(len(selections), 1)
+        selections.add(resource.textselection(Offset.simple(6,11)))
+        self.assertEqual(len(selections), 2)
+
+    def test_textselection_intersection(self):
+        resource = self.store.resource("testres")
+        textselection = resource.textselection(Offset.simple(0,8))
+        self.assertEqual(str(textselection.intersection(resource.textselection(Offset.simple(4,10)))), "o wo")
+        self.assertIsNone(textselection.intersection(resource.textselection(Offset.simple(8,11))))
+
+    def test_annotate_shortcut_targets(self):
+        resource = self.store.resource("testres")
+        annotation = self.store.annotate(id="A3",
+                            target=resource.textselection(Offset.simple(0,5)),
+                            data=[AnnotationDataBuilder(key="type", value="token", annotationset="testdataset")])
+        self.assertEqual(str(annotation), "Hello")
+        annotation = self.store.annotate(id="A4",
+                            target=(resource, Offset.simple(6,11)),
+                            data=[AnnotationDataBuilder(key="type", value="token", annotationset="testdataset")])
+        self.assertEqual(str(annotation), "world")
+        annotation = self.store.annotate(id="A5",
+                            target=self.store.annotation("A1"),
+                            data=[AnnotationDataBuilder(key="type", value="token", annotationset="testdataset")])
+        self.assertEqual(annotation.annotations(), (self.store.annotation("A1"),))
+
+class Test5(unittest.TestCase):
+    def setUp(self):
+        self.store = AnnotationStore(id="test")
+        self.store.add_resource(id="mixed", text="Hello שלום")
+
+    def test_scripts(self):
+        resource = self.store.resource("mixed")
+        self.assertEqual(resource.scripts(), ["Latin","Hebrew"])
+
+    def test_base_direction(self):
+        resource = self.store.resource("mixed")
+        self.assertEqual(resource.textselection(Offset.simple(0,5)).base_direction(), "ltr")
+        self.assertEqual(resource.textselection(Offset.simple(6,10)).base_direction(), "rtl")
+        self.assertEqual(resource.textselection(Offset.whole()).base_direction(), "mixed")
+
+    def test_split_text_regex(self):
+        store = AnnotationStore(id="test")
+        resource = store.add_resource(id="paragraphs", text="First paragraph.\n  \nSecond one.\n\nThird.")
+        paragraphs = resource.split_text_regex(r"\n\s*\n")
+        self.assertEqual([ str(p) for p in paragraphs ], ["First paragraph.", "Second one.", "Third."])
+        self.assertEqual(paragraphs[1].begin(), 20)
+        self.assertEqual(len(resource.split_text_regex(r"\n\s*\n", limit=2)), 2)
+        words = paragraphs[1].split_text_regex(r"\s+")
+        self.assertEqual([ str(w) for w in words ], ["Second", "one."])
+
+    def test_find_text_fold(self):
+        store = AnnotationStore(id="test")
+        resource = store.add_resource(id="historical", text="Het Café en het cafe")
+        self.assertEqual([ str(x) for x in resource.find_text("Het") ], ["Het"])
+        self.assertEqual([ str(x) for x in resource.find_text("het", fold="case") ], ["Het", "het"])
+        self.assertEqual([ str(x) for x in resource.find_text("cafe", fold="case") ], ["cafe"])
+        textselections = resource.find_text("CAFE", fold="case+diacritics")
+        self.assertEqual([ str(x) for x in textselections ], ["Café", "cafe"])
+        self.assertEqual(textselections[0].end(), 8)
+
+    def test_find_text_regex(self):
+        store = AnnotationStore(id="test")
+        resource = store.add_resource(id="dates", text="Born 1879-03-14, died 1955-04-18.")
+        self.assertEqual([ str(x) for x in resource.find_text_regex(r"\d{4}") ], ["1879", "1955"])
+        matches = resource.find_text_regex(r"(?P<year>\d{4})-(?P<month>\d{2})-(?P<day>\d{2})", named_groups=True)
+        self.assertEqual(len(matches), 2)
+        self.assertEqual(str(matches[0]["year"]), "1879")
+        self.assertEqual(str(matches[1]["day"]), "18")
+        self.assertEqual(matches[1]["month"].begin(), 27)
+
+    def test_merge_adjacent(self):
+        store = AnnotationStore(id="test")
+        resource = store.add_resource(id="tokens", text="New York, New Jersey")
+        for begin, end in ((0,3),(4,8),(8,9),(10,13),(14,20)):
+            store.annotate(target=(resource, Offset.simple(begin,end)), data=[AnnotationDataBuilder(key="type", value="token", annotationset="tokens")])
+        self.assertEqual([ str(x) for x in iter(resource).merge_adjacent() ], ["New York, New Jersey"])
+        self.assertEqual([ str(x) for x in iter(resource).merge_adjacent(allow_whitespace=False) ], ["New", "York,", "New", "Jersey"])
+        self.assertEqual([ str(x) for x in iter(resource).merge_adjacent(max_gap=1, allow_whitespace=False) ], ["New York, New Jersey"])
+
+    def test_annotate_regex(self):
+        store = AnnotationStore(id="test")
+        resource = store.add_resource(id="dates", text="Born 1879-03-14, died 1955-04-18.")
+        annotations = resource.annotate_regex(r"\d{4}-\d{2}-\d{2}", [AnnotationDataBuilder(key="type", value="date", annotationset="entities")], id_prefix="date")
+        self.assertEqual([ str(a) for a in annotations ], ["1879-03-14", "1955-04-18"])
+        self.assertEqual(annotations[1].id, "date1")
+        annotations = resource.annotate_regex(r"(?P<year>\d{4})-\d{2}", [AnnotationDataBuilder(key="type", value="year", annotationset="entities")], group="year", limit=1)
+        self.assertEqual([ str(a) for a in annotations ], ["1879"])
+
+    def test_find_any_text(self):
+        store = AnnotationStore(id="test")
+        resource = store.add_resource(id="gazetteer", text="From Amsterdam to New York via York")
+        matches = resource.find_any_text(["Amsterdam", "New York", "York"])
+        self.assertEqual([ (str(textselection), fragment) for textselection, fragment in matches ], [("Amsterdam",0), ("New York",1), ("York",2)])
+        matches = resource.find_any_text(["new york", "york"], case_sensitive=False, allow_overlap=True)
+        self.assertEqual(len(matches), 3)
+
+    def test_segmentation(self):
+        store = AnnotationStore(id="test")
+        resource = store.add_resource(id="text", text="Hello world! How are you? I'm fine")
+        self.assertEqual([ str(s) for s in resource.sentences() ], ["Hello world!", "How are you?", "I'm fine"])
+        self.assertEqual([ str(w) for w in resource.sentences()[1].words() ], ["How", "are", "you"])
+        words = resource.words(segmenter=lambda text: [ (0,5), (6,11) ])
+        self.assertEqual([ str(w) for w in words ], ["Hello", "world"])
+        with self.assertRaises(ValueError):
+            resource.sentences()[0].words(segmenter=lambda text: [ (0, 20) ])
+        with self.assertRaises(ValueError):
+            resource.words(segmenter=lambda text: [ (5, 0) ])
+
+class Test6(unittest.TestCase):
+    def setUp(self):
+        self.store = AnnotationStore(id="test")
+        resource = self.store.add_resource(id="testres", text="Hello world")
+        self.store.annotate(id="Phrase",
+                            target=Selector.text(resource, Offset.simple(0,11)),
+                            data=[AnnotationDataBuilder(id="D1", key="type", value="phrase", annotationset="testdataset")])
+        self.store.annotate(id="A1",
+                            target=Selector.text(resource, Offset.simple(0,5)),
+                            data=[AnnotationDataBuilder(id="D2", key="type", value="word", annotationset="testdataset")])
+        self.store.annotate(id="A2",
+                            target=Selector.text(resource, Offset.simple(6,11)),
+                            data=[AnnotationDataBuilder.link(self.store.annotationset("testdataset").annotationdata("D2"))])
+
+    def test_resource_annotations_len(self):
+        resource = self.store.resource("testres")
+        self.assertEqual(resource.annotations_len(), 3)
+        self.assertEqual(resource.annotations_as_metadata_len(), 0)
+        self.store.annotate(id="Metadata",
+                            target=Selector.resource(resource),
+                            data=[AnnotationDataBuilder(key="genre", value="greeting", annotationset="testdataset")])
+        self.assertEqual(resource.annotations_len(), 4)
+        self.assertEqual(resource.annotations_as_metadata_len(), 1)
+
+    def test_filter_score(self):
+        dataset = self.store.add_annotationset("scores")
+        dataset.add_data("confidence", 0.9, "high")
+        dataset.add_data("confidence", 0.2, "low")
+        self.store.annotate(id="S1", target=self.store.annotation("A1"), data=[AnnotationDataBuilder.link(dataset.annotationdata("high"))])
+        self.store.annotate(id="S2", target=self.store.annotation("A2"), data=[AnnotationDataBuilder.link(dataset.annotationdata("low"))])
+        annotations = self.store.annotations().filter_score(dataset.key("confidence"), min=0.5)
+        self.assertEqual(annotations, (self.store.annotation("S1"),))
+        annotations = self.store.annotations().filter_score(dataset.key("confidence"), max=0.5)
+        self.assertEqual(annotations, (self.store.annotation("S2"),))
+
+    def test_agreement(self):
+        resource = self.store.resource("testres")
+        dataset = self.store.annotationset("testdataset")
+        b1 = self.store.annotate(id="B1", target=(resource, Offset.simple(0,5)), data=[AnnotationDataBuilder.link(dataset.annotationdata("D2"))])
+        b2 = self.store.annotate(id="B2", target=(resource, Offset.simple(6,9)), data=[AnnotationDataBuilder.link(dataset.annotationdata("D2"))])
+        result = self.store.agreement([self.store.annotation("A1"), self.store.annotation("A2")], [b1, b2], key=dataset.key("type"))
+        self.assertEqual(result["true_positives"], 1)
+        self.assertEqual(result["precision"], 0.5)
+        self.assertEqual(result["recall"], 0.5)
+        self.assertEqual(result["label_accuracy"], 1.0)
+
+    def test_agreement_edge_cases(self):
+        resource = self.store.resource("testres")
+        dataset = self.store.add_annotationset("numbers")
+        dataset.add_data("n", 1, "N1")
+        dataset.add_data("n", "1", "N2")
+        #annotations without text never match
+        m1 = self.store.annotate(id="M1", target=Selector.resource(resource), data=[AnnotationDataBuilder.link(dataset.annotationdata("N1"))])
+        m2 = self.store.annotate(id="M2", target=Selector.resource(resource), data=[AnnotationDataBuilder.link(dataset.annotationdata("N1"))])
+        self.assertEqual(self.store.agreement([m1], [m2])["true_positives"], 0)
+        #labels are compared along with their type
+        n1 = self.store.annotate(id="N1", target=(resource, Offset.simple(0,5)), data=[AnnotationDataBuilder.link(dataset.annotationdata("N1"))])
+        n2 = self.store.annotate(id="N2", target=(resource, Offset.simple(0,5)), data=[AnnotationDataBuilder.link(dataset.annotationdata("N2"))])
+        result = self.store.agreement([n1], [n2], key=dataset.key("n"))
+        self.assertEqual(result["true_positives"], 1)
+        self.assertEqual(result["label_accuracy"], 0.0)
+        copy = AnnotationStore(string=self.store.to_string())
+        with self.assertRaises(ValueError):
+            self.store.agreement([n1], [copy.annotation("N2")])
+
+    def test_content_equals(self):
+        resource = self.store.resource("testres")
+        dataset = self.store.annotationset("testdataset")
+        duplicate = self.store.annotate(id="A1b", target=(resource, Offset.simple(0,5)), data=[AnnotationDataBuilder.link(dataset.annotationdata("D2"))])
+        self.assertNotEqual(duplicate, self.store.annotation("A1"))
+        self.assertTrue(duplicate.content_equals(self.store.annotation("A1")))
+        self.assertFalse(duplicate.content_equals(self.store.annotation("A2")))
+        other = AnnotationStore(string=self.store.to_string())
+        self.assertTrue(other.annotation("A1").content_equals(self.store.annotation("A1")))
+
+    def test_content_equals_typed(self):
+        resource = self.store.resource("testres")
+        dataset = self.store.add_annotationset("numbers")
+        dataset.add_data("n", 1, "N1")
+        dataset.add_data("n", "1", "N2")
+        int_annotation = self.store.annotate(id="I", target=(resource, Offset.simple(0,5)), data=[AnnotationDataBuilder.link(dataset.annotationdata("N1"))])
+        str_annotation = self.store.annotate(id="S", target=(resource, Offset.simple(0,5)), data=[AnnotationDataBuilder.link(dataset.annotationdata("N2"))])
+        self.assertFalse(int_annotation.content_equals(str_annotation))
+
+    def test_equivalent(self):
+        copy = AnnotationStore(string=self.store.to_string())
+        self.assertTrue(self.store.annotation("A1").equivalent(copy.annotation("A1")))
+        self.assertFalse(self.store.annotation("A1").equivalent(copy.annotation("A2")))
+        self.assertNotEqual(self.store.annotation("A1"), copy.annotation("A1"))
+        resource = self.store.resource("testres")
+        dataset = self.store.annotationset("testdataset")
+        multi = self.store.annotate(id="M", target=Selector.multi(Selector.text(resource, Offset.simple(0,5))), data=[AnnotationDataBuilder.link(dataset.annotationdata("D2"))])
+        self.assertTrue(multi.content_equals(self.store.annotation("A1")))
+        self.assertFalse(multi.equivalent(self.store.annotation("A1")))
+
+    def test_equivalent_anonymous(self):
+        resource = self.store.resource("testres")
+        dataset = self.store.annotationset("testdataset")
+        anonymous1 = self.store.annotate(target=(resource, Offset.simple(0,5)), data=[AnnotationDataBuilder.link(dataset.annotationdata("D2"))])
+        anonymous2 = self.store.annotate(target=(resource, Offset.simple(6,11)), data=[AnnotationDataBuilder.link(dataset.annotationdata("D2"))])
+        x = self.store.annotate(id="X", target=Selector.annotation(anonymous1, Offset.whole()), data=[AnnotationDataBuilder.link(dataset.annotationdata("D2"))])
+        y = self.store.annotate(id="Y", target=Selector.annotation(anonymous2, Offset.whole()), data=[AnnotationDataBuilder.link(dataset.annotationdata("D2"))])
+        self.assertFalse(x.equivalent(y))
+        self.assertFalse(x.equivalent(x))
+
+    def test_equivalent_multiple_annotations(self):
+        dataset = self.store.annotationset("testdataset")
+        data = [AnnotationDataBuilder.link(dataset.annotationdata("D2"))]
+        x = self.store.annotate(id="X", target=[self.store.annotation("Phrase"), self.store.annotation("A1")], data=data)
+        y = self.store.annotate(id="Y", target=[self.store.annotation("A1"), self.store.annotation("A2")], data=data)
+        self.assertFalse(x.equivalent(y))
+        copy = AnnotationStore(string=self.store.to_string())
+        self.assertTrue(x.equivalent(copy.annotation("X")))
+
+    def test_annotate_subspan(self):
+        phrase = self.store.annotation("Phrase")
+        annotation = phrase.annotate_subspan("world", [AnnotationDataBuilder(key="type", value="noun", annotationset="testdataset")], id="Sub")
+        self.assertEqual(annotation.id, "Sub")
+        self.assertEqual(str(annotation), "world")
+        self.assertEqual(annotation.annotations(), (phrase,))
+        self.assertIsNone(phrase.annotate_subspan("moon", []))
+
+    def test_overlapping(self):
+        phrase = self.store.annotation("Phrase")
+        self.assertEqual(set(a.id for a in phrase.overlapping()), {"A1", "A2"})
+        self.assertEqual(set(a.id for a in self.store.annotation("A1").overlapping()), {"Phrase"})
+        key = self.store.annotationset("testdataset").key("type")
+        self.assertEqual(set(a.id for a in self.store.annotation("A1").overlapping(key, "word")), set())
+        self.assertEqual(set(a.id for a in self.store.annotation("A1").overlapping(key, "phrase")), {"Phrase"})
+
+    def test_validate_against(self):
+        dataset = self.store.annotationset("testdataset")
+        self.assertEqual(dataset.validate_against({"type": {"type": "str", "values": ["phrase", "word"]}}), [])
+        violations = dataset.validate_against({"type": {"type": "str", "values": ["phrase"]}})
+        self.assertEqual(len(violations), 1)
+        self.assertEqual(violations[0]["key"], "type")
+        self.assertEqual(violations[0]["value"], "word")
+        self.assertEqual(len(violations[0]["data"].annotations()), 2)
+        self.assertEqual(sorted(a.id for a in violations[0]["annotations"]), ["A1", "A2"])
+        dataset.add_data("confidence", 1.5, "C1")
+        violations = dataset.validate_against({"type": None, "confidence": {"type": ["int", "float"], "min": 0, "max": 1}})
+        self.assertEqual([ v["key"] for v in violations ], ["confidence"])
+        violations = dataset.validate_against({"type": None})
+        self.assertEqual(violations[0]["message"], "key is not declared in the schema")
+
+    def test_annotate_dry_run(self):
+        resource = self.store.resource("testres")
+        result = self.store.annotate(id="New", target=(resource, Offset.simple(0,5)), data=["D2"], dry_run=True)
+        self.assertEqual(result["id"], "New")
+        self.assertEqual(result["data"], 1)
+        self.assertEqual(self.store.annotations_len(), 3)
+        with self.assertRaises(StamError):
+            self.store.annotate(id="New", target=(resource, Offset.simple(0,50)), data=["D2"], dry_run=True)
+        with self.assertRaises(StamError):
+            self.store.annotate(id="A1", target=(resource, Offset.simple(0,5)), data=["D2"], dry_run=True)
+        with self.assertRaises(StamError):
+            self.store.annotate(id="New", target=Selector.annotation(self.store.annotation("A1"), Offset.simple(2,10)), data=["D2"], dry_run=True)
+        result = self.store.annotate(id="New", target=Selector.annotation(self.store.annotation("A1"), Offset.simple(1,3)), data=["D2"], dry_run=True)
+        self.assertEqual(result["id"], "New")
+        other = AnnotationStore(id="other")
+        otherset = other.add_annotationset("otherset")
+        for i in range(10):
+            otherset.add_data("n", i, f"N{i}")
+        with self.assertRaises(StamError):
+            #links to a data handle that does not exist in this store
+            self.store.annotate(id="New", target=(resource, Offset.simple(0,5)), data=[AnnotationDataBuilder.link(otherset.annotationdata("N9"))], dry_run=True)
+        self.assertEqual(self.store.annotations_len(), 3)
+
+    def test_add_annotations_from_dataframe(self):
+        class Records:
+            """Minimal stand-in for a pandas DataFrame"""
+            def __init__(self, records):
+                self.records = records
+            def to_dict(self, orient):
+                assert orient == "records"
+                return self.records
+        resource = self.store.resource("testres")
+        df = Records([
+            { "id": "T1", "begin": 0, "end": 5, "key": "pos", "value": "interjection" },
+            { "id": "T1", "begin": 0, "end": 5, "key": "lemma", "value": "hello" },
+            { "id": "T2", "begin": 6, "end": 11, "key": "pos", "value": "noun" },
+        ])
+        annotations = self.store.add_annotations_from_dataframe(df, resource, annotationset="tokens", id_column="id")
+        self.assertEqual([ a.id for a in annotations ], ["T1", "T2"])
+        self.assertEqual(str(annotations[0]), "Hello")
+        self.assertEqual(len(list(annotations[0])), 2)
+        with self.assertRaises(ValueError):
+            self.store.add_annotations_from_dataframe(Records([{ "begin": 0, "end": 5, "key": "pos", "value": "x" }]), resource)
+
+    def test_annotations_without_data(self):
+        self.assertEqual(self.store.annotations_without_data(), ())
+        resource = self.store.resource("testres")
+        bare = self.store.annotate(id="Bare", target=(resource, Offset.simple(0,5)), data=[])
+        self.assertEqual(self.store.annotations_without_data(), (bare,))
+
+    def test_text_frequencies(self):
+        resource = self.store.resource("testres")
+        self.store.annotate(id="A3", target=(resource, Offset.simple(0,5)), data=["D2"])
+        self.assertEqual(self.store.annotations().text_frequencies(), {"Hello world": 1, "Hello": 2, "world": 1})
+        self.store.add_resource(id="other", text=" HELLO ")
+        self.store.annotate(id="A4", target=(self.store.resource("other"), Offset.simple(0,7)), data=["D2"])
+        frequencies = self.store.annotations().text_frequencies(case_sensitive=False, normalize="strip")
+        self.assertEqual(frequencies["hello"], 3)
+
+    def test_covering_within_annotations(self):
+        resource = self.store.resource("testres")
+        textselection = resource.textselection(Offset.simple(1,4))
+        self.assertEqual(set(a.id for a in textselection.covering_annotations()), {"Phrase", "A1"})
+        key = self.store.annotationset("testdataset").key("type")
+        self.assertEqual(set(a.id for a in textselection.covering_annotations(key, "phrase")), {"Phrase"})
+        textselection = resource.textselection(Offset.simple(0,8))
+        self.assertEqual(set(a.id for a in textselection.within_annotations()), {"A1"})
+        self.assertEqual(set(a.id for a in resource.textselection(Offset.simple(0,11)).within_annotations()), {"Phrase", "A1", "A2"})
+
+    def test_to_markup(self):
+        resource = self.store.resource("testres")
+        key = self.store.annotationset("testdataset").key("type")
+        self.assertEqual(resource.to_markup(), "[[Hello] [world]]")
+        self.assertEqual(resource.to_markup(key, "word", open="<", close=">"), "<Hello> <world>")
+        self.assertEqual(resource.to_markup(label=key), "[[Hello|word] [world|word]|phrase]")
+
+    def test_total_textlen(self):
+        self.store.add_resource(id="other", text="Goodbye, world!")
+        self.assertEqual(self.store.total_textlen(), 26)
+        self.assertEqual(self.store.estimated_tokens(), 6)
+        self.assertEqual(self.store.estimated_tokens(pattern=r"\S+"), 4)
+        #the cached estimate is invalidated when resources are added
+        self.store.add_resource(id="third", text="One more")
+        self.assertEqual(self.store.estimated_tokens(), 8)
+
+    def test_diff(self):
+        other = AnnotationStore(string=self.store.to_string())
+        resource = other.resource("testres")
+        dataset = other.annotationset("testdataset")
+        other.annotate(id="A3", target=(resource, Offset.simple(0,11)), data=[AnnotationDataBuilder.link(dataset.annotationdata("D1"))])
+        diff = self.store.diff(other)
+        self.assertEqual([ a["id"] for a in diff["annotations"]["added"] ], ["A3"])
+        self.assertEqual(diff["annotations"]["added"][0]["spans"], [("testres", 0, 11)])
+        self.assertEqual(diff["annotations"]["added"][0]["data"], [("testdataset", "type", "phrase")])
+        self.assertEqual(diff["annotations"]["removed"], [])
+        self.assertEqual(diff["annotations"]["changed"], [])
+        self.assertEqual(diff["resources"], {"added": [], "removed": [], "changed": []})
+        self.assertEqual(self.store.diff(self.store)["annotations"]["added"], [])
+
+    def test_diff_value_type(self):
+        other = AnnotationStore(string=self.store.to_string())
+        for store, value in ((self.store, "1"), (other, 1)):
+            dataset = store.add_annotationset("numbers")
+            dataset.add_data("n", value, "N")
+            store.annotate(id="X", target=(store.resource("testres"), Offset.simple(0,5)), data=[AnnotationDataBuilder.link(dataset.annotationdata("N"))])
+        diff = self.store.diff(other)
+        self.assertEqual(diff["annotationsets"]["changed"], ["numbers"])
+        self.assertEqual([ a["id"] for a in diff["annotations"]["changed"] ], ["X"])
+        self.assertEqual(diff["annotations"]["changed"][0]["new"]["data"], [("numbers", "n", 1)])
+
+    def test_filter(self):
+        annotations = self.store.annotations().filter(lambda annotation: str(annotation).istitle())
+        self.assertEqual([a.id for a in annotations], ["A1"])
+        # the predicate may query the store
+        annotations = self.store.annotations().filter(lambda annotation: len(str(self.store.annotation(annotation.id))) > 5)
+        self.assertEqual([a.id for a in annotations], ["Phrase"])
+
+    def test_bounding(self):
+        textselection = self.store.annotations().bounding_selection()
+        self.assertEqual((textselection.begin(), textselection.end()), (0, 11))
+        resource = self.store.resource("testres")
+        dataset = self.store.annotationset("testdataset")
+        b1 = self.store.annotate(id="B1", target=(resource, Offset.simple(2,5)), data=[AnnotationDataBuilder.link(dataset.annotationdata("D2"))])
+        b2 = self.store.annotate(id="B2", target=(resource, Offset.simple(6,9)), data=[AnnotationDataBuilder.link(dataset.annotationdata("D2"))])
+        annotations = self.store.annotations()
+        for _ in range(3):
+            next(annotations)
+        annotation = annotations.bounding_annotation([AnnotationDataBuilder(key="type", value="span", annotationset="testdataset")], id="Span")
+        self.assertEqual(annotation.id, "Span")
+        self.assertEqual(str(annotation), "llo wor")
+        self.store.add_resource(id="other", text="Goodbye")
+        self.store.annotate(id="O1", target=(self.store.resource("other"), Offset.simple(0,4)), data=[])
+        with self.assertRaises(ValueError):
+            self.store.annotations().bounding_selection()
+
+    def test_snapshot(self):
+        annotations = self.store.annotations().snapshot()
+        self.assertIsInstance(annotations, list)
+        self.assertEqual([a.id for a in annotations], ["Phrase", "A1", "A2"])
+        for annotation in annotations:
+            self.store.annotate(target=annotation, data=[AnnotationDataBuilder(key="checked", value=True, annotationset="testdataset")])
+        self.assertEqual(self.store.annotations_len(), 6)
+        self.assertEqual(len(self.store.annotation("A1").__iter__().snapshot()), 1)
+        self.assertEqual(len(self.store.annotationset("testdataset").__iter__().snapshot()), 3)
+        self.assertEqual(len(self.store.resource("testres").textselections().snapshot()), 3)
+
+    def test_relations(self):
+        phrase = self.store.annotation("Phrase")
+        a1 = self.store.annotation("A1")
+        a2 = self.store.annotation("A2")
+        self.assertTrue(phrase.overlaps(a1))
+        self.assertFalse(a1.overlaps(a2))
+        self.assertTrue(phrase.embeds(a2))
+        self.assertTrue(a2.embedded_in(phrase))
+        self.assertFalse(phrase.embedded_in(a2))
+        self.assertTrue(a1.before(a2))
+        self.assertTrue(a2.after(a1))
+        self.assertFalse(a1.precedes(a2))
+        resource = self.store.resource("testres")
+        hello = resource.textselection(Offset.simple(0,5))
+        space = resource.textselection(Offset.simple(5,6))
+        self.assertTrue(hello.precedes(space))
+        self.assertTrue(space.succeeds(hello))
+        self.assertFalse(space.succeeds(space))
+        #objects from different stores can not be related
+        copy = AnnotationStore(string=self.store.to_string())
+        with self.assertRaises(ValueError):
+            a1.overlaps(copy.annotation("A1"))
+        with self.assertRaises(ValueError):
+            hello.overlaps(copy.resource("testres").textselection(Offset.simple(0,5)))
+        with self.assertRaises(ValueError):
+            hello.overlap_length(copy.resource("testres").textselection(Offset.simple(0,5)))
+
+    def test_datavalue_types(self):
+        dataset = self.store.annotationset("testdataset")
+        self.assertEqual(dataset.add_data("flag", True, "T").value().get(), True)
+        self.assertEqual(dataset.key("flag").value_types(), {"Bool"})
+        self.assertEqual(dataset.add_data("count", 1, "I").value().get(), 1)
+        self.assertEqual(dataset.key("count").value_types(), {"Int"})
+        self.assertEqual(dataset.add_data("ratio", 1.0, "F").value().get(), 1.0)
+        self.assertEqual(dataset.key("ratio").value_types(), {"Float"})
+        self.assertEqual(dataset.add_data("label", "1", "S").value().get(), "1")
+        self.assertEqual(dataset.key("label").value_types(), {"String"})
+        self.assertEqual(dataset.add_data("coerced", "1", "C1", coerce="int").value().get(), 1)
+        self.assertEqual(dataset.add_data("coerced", 1, "C2", coerce="bool").value().get(), True)
+        self.assertEqual(dataset.add_data("coerced", True, "C3", coerce="str").value().get(), "true")
+        self.assertEqual(dataset.key("coerced").value_types(), {"Int", "Bool", "String"})
+        with self.assertRaises(ValueError):
+            dataset.add_data("coerced", "x", "C4", coerce="int")
+
+    def test_used_keys(self):
+        dataset = self.store.annotationset("testdataset")
+        dataset.add_key("unused")
+        used = self.store.used_keys()
+        self.assertEqual(len(used), 1)
+        self.assertEqual(used[0][0], dataset)
+        self.assertEqual(used[0][1], dataset.key("type"))
+
+    def test_value_types(self):
+        dataset = self.store.annotationset("testdataset")
+        self.assertEqual(dataset.key("type").value_types(), {"String"})
+        dataset.add_data("type", 1, "D3")
+        self.assertEqual(dataset.key("type").value_types(), {"String", "Int"})
+
+    def test_annotationset_annotations(self):
+        dataset = self.store.annotationset("testdataset")
+        self.assertEqual(sorted(a.id for a in dataset.annotations()), ["A1", "A2", "Phrase"])
+        self.assertEqual(sorted(a.id for a in dataset.annotations(key="type", value="word")), ["A1", "A2"])
+        self.assertEqual([a.id for a in dataset.annotations(value="phrase")], ["Phrase"])
+        self.assertEqual(dataset.annotations(key="nonexistent"), ())
+
+    def test_has_annotations(self):
+        self.assertTrue(self.store.resource("testres").has_annotations())
+        self.assertFalse(self.store.add_resource(id="other", text="Goodbye").has_annotations())
+
+    def test_resource_annotations(self):
+        resource = self.store.resource("testres")
+        self.assertEqual(sorted(a.id for a in resource.annotations()), ["A1", "A2", "Phrase"])
+        self.assertEqual(sorted(a.id for a in resource.annotations(range=(0,3))), ["A1", "Phrase"])
+        self.assertEqual(sorted(a.id for a in resource.annotations(range=(7,20))), ["A2", "Phrase"])
+        self.assertEqual(resource.annotations(range=(11,20)), ())
+
+    def test_find_text_within(self):
+        resource = self.store.resource("testres")
+        self.assertEqual(len(resource.find_text("o")), 2)
+        self.assertEqual([t.begin() for t in resource.find_text("o", within="A2")], [7])
+        self.assertEqual([t.begin() for t in resource.find_text("o", within=self.store.annotation("A1"))], [4])
+        self.assertEqual([t.begin() for t in resource.find_text("o", outside="A1")], [7])
+        self.assertEqual(resource.find_text("o", outside="Phrase"), ())
+
+    def test_offsets(self):
+        self.store.add_resource(id="emoji", text="👋 Hello café!")
+        resource = self.store.resource("emoji")
+        offsets = resource.textselection(Offset.simple(8,12)).offsets()
+        self.assertEqual(offsets["char"], (8, 12))
+        self.assertEqual(offsets["utf8byte"], (11, 16))
+        self.assertEqual(offsets["utf16"], (9, 13))
+
+    def test_utf16(self):
+        self.store.add_resource(id="emoji", text="👋 Hello café!")
+        resource = self.store.resource("emoji")
+        self.assertEqual(resource.utf16unit(2), 3)
+        self.assertEqual(resource.utf16unit_to_charpos(3), 2)
+        self.assertEqual(resource.utf16unit_to_charpos(14), 13)
+        with self.assertRaises(IndexError):
+            resource.utf16unit_to_charpos(1)
+        with self.assertRaises(IndexError):
+            resource.utf16unit(14)
+        textselection = resource.textselection(Offset.simple(0,7))
+        self.assertEqual(textselection.utf16unit(7), 8)
+        self.assertEqual(textselection.utf16unit_to_charpos(8), 7)
+
+    def test_tags(self):
+        resource = self.store.resource("testres")
+        textselection = resource.textselection(Offset.simple(0,5))
+        self.assertEqual(textselection.tags(), ())
+        self.store.tag(textselection, "greeting")
+        self.store.tag(textselection, "interjection")
+        self.store.tag(resource.textselection(Offset.simple(6,11)), "noun")
+        self.assertEqual(textselection.tags(), ("greeting", "interjection"))
+        self.assertEqual(textselection.tags(set="other"), ())
+
+    def test_suggest_replacement(self):
+        resource = self.store.resource("testres")
+        annotations = resource.suggest_replacement("world", "World", id_prefix="C")
+        self.assertEqual(len(annotations), 1)
+        self.assertEqual(str(annotations[0]), "world")
+        self.assertEqual(str(resource), "Hello world")
+        data = list(annotations[0])
+        self.assertEqual(data[0].key().id, "replacement")
+        self.assertEqual(str(data[0].value()), "World")
+        self.assertTrue(self.store.annotationset("corrections").has_id("corrections"))
+
+    def test_layers(self):
+        resource = self.store.resource("testres")
+        layers = { annotation.id: lane for annotation, lane in resource.layers() }
+        self.assertEqual(layers, {"Phrase": 0, "A1": 1, "A2": 1})
+
+class Test7(unittest.TestCase):
+    def test_selector_walk(self):
+        store = AnnotationStore(id="test")
+        resource = store.add_resource(id="testres", text="Hello world")
+        selector = Selector.composite(Selector.text(resource, Offset.simple(0,5)), Selector.multi(Selector.resource(resource), Selector.text(resource, Offset.simple(6,11))))
+        visited = []
+        selector.walk(lambda selector, kind, depth, offset: visited.append((depth, selector.is_textselector(), offset.begin().value() if offset else None)))
+        self.assertEqual(visited, [(0, False, None), (1, True, 0), (1, False, None), (2, False, None), (2, True, 6)])
+        visited = []
+        selector.walk(lambda selector, kind, depth, offset: visited.append(depth) or not selector.is_multiselector())
+        self.assertEqual(visited, [0, 1, 1])
+
+    def test_cross_resource_annotations(self):
+        store = AnnotationStore(id="test")
+        source = store.add_resource(id="source", text="Hello world")
+        target = store.add_resource(id="target", text="Hallo wereld")
+        store.annotate(id="A1", target=Selector.text(source, Offset.simple(0,5)), data=[AnnotationDataBuilder(key="type", value="word", annotationset="testdataset")])
+        store.annotate(id="Alignment", target=Selector.multi(Selector.text(source, Offset.simple(0,5)), Selector.text(target, Offset.simple(0,5))), data=[AnnotationDataBuilder(key="type", value="alignment", annotationset="testdataset")])
+        store.annotate(id="Translation", target=Selector.multi(Selector.resource(source), Selector.resource(target)), data=[AnnotationDataBuilder(key="type", value="translation", annotationset="testdataset")])
+        self.assertEqual([a.id for a in store.cross_resource_annotations()], ["Alignment", "Translation"])
+        key = store.annotationset("testdataset").key("type")
+        self.assertEqual([a.id for a in store.cross_resource_annotations(key=key, value="alignment")], ["Alignment"])
+
+    def test_textselector_bytes(self):
+        store = AnnotationStore(id="test")
+        resource = store.add_resource(id="testres", text="Hello café world")
+        annotation = store.annotate(id="A1", target=Selector.textselector_bytes(resource, 6, 11), data=[])
+        self.assertEqual(str(annotation), "café")
+        self.assertEqual(annotation.textselections()[0].begin(), 6)
+        self.assertEqual(annotation.textselections()[0].end(), 10)
+        with self.assertRaises(ValueError):
+            Selector.textselector_bytes(resource, 6, 10)
+
+    def test_merge_overlapping(self):
+        store = AnnotationStore(id="test")
+        resource = store.add_resource(id="testres", text="Barack Obama visited New York City")
+        for i, (begin, end, value) in enumerate([(0,6,"PER"), (7,12,"PER"), (13,20,"O"), (21,24,"LOC"), (25,29,"LOC"), (30,34,"LOC")]):
+            store.annotate(id=f"T{i}", target=(resource, Offset.simple(begin,end)), data=[AnnotationDataBuilder(key="entity", value=value, annotationset="ner")])
+        self.assertEqual(store.merge_overlapping("entity"), ())
+        merged = store.merge_overlapping("entity", set="ner", allow_whitespace=True, id_prefix="E")
+        self.assertEqual(sorted(str(annotation) for annotation in merged), ["Barack Obama", "New York City"])
+        self.assertTrue(all(annotation.id.startswith("E") for annotation in merged))
+        self.assertEqual(store.annotations_len(), 8)
+        #merging again adds nothing, the merged spans are already covered exactly
+        self.assertEqual(store.merge_overlapping("entity", set="ner", allow_whitespace=True), ())
+
+    def test_merge_overlapping_single_source(self):
+        store = AnnotationStore(id="test")
+        resource = store.add_resource(id="testres", text="New York")
+        data = [AnnotationDataBuilder(key="entity", value="LOC", annotationset="ner")]
+        #a single discontinuous annotation is not restated
+        store.annotate(id="D", target=Selector.multi(Selector.text(resource, Offset.simple(0,3)), Selector.text(resource, Offset.simple(4,8))), data=data)
+        self.assertEqual(store.merge_overlapping("entity", allow_whitespace=True), ())
+        #nor are two annotations with identical spans
+        store.annotate(id="X1", target=(resource, Offset.simple(0,8)), data=data)
+        store.annotate(id="X2", target=(resource, Offset.simple(0,8)), data=data)
+        self.assertEqual(store.merge_overlapping("entity"), ())
+        self.assertEqual(store.annotations_len(), 3)
+
+    def test_concat_resources(self):
+        store = AnnotationStore(id="test")
+        first = store.add_resource(id="first", text="Hello")
+        second = store.add_resource(id="second", text="world")
+        combined = store.concat_resources([first, second], "combined", separator=" ")
+        other = AnnotationStore(id="other")
+        with self.assertRaises(ValueError):
+            store.concat_resources([first, other.add_resource(id="foreign", text="!")], "invalid")
+        self.assertEqual(str(combined), "Hello world")
+        self.assertEqual(store.annotations_len(), 2)
+        mapping = list(store.annotations())[1]
+        textselections = mapping.textselections()
+        self.assertEqual([(t.resource().id, t.begin(), t.end()) for t in textselections], [("second", 0, 5), ("combined", 6, 11)])
+
+    def test_group_by(self):
+        store = AnnotationStore(id="test")
+        resource = store.add_resource(id="testres", text="The big dog barks")
+        for i, (begin, end, pos) in enumerate([(0,3,"det"), (4,7,"adj"), (8,11,"noun"), (12,17,"verb")]):
+            store.annotate(id=f"W{i}", target=(resource, Offset.simple(begin,end)), data=[AnnotationDataBuilder(key="pos", value=pos, annotationset="pos")])
+        store.annotate(id="S", target=(resource, Offset.simple(0,17)), data=[AnnotationDataBuilder(key="type", value="sentence", annotationset="pos")])
+        store.annotate(id="W1b", target=(resource, Offset.simple(4,7)), data=[AnnotationDataBuilder(key="pos", value="adj", annotationset="pos")])
+        groups = store.annotations().group_by("pos", "pos")
+        self.assertEqual({ value: [a.id for a in annotations] for value, annotations in groups.items() }, {"det": ["W0"], "adj": ["W1", "W1b"], "noun": ["W2"], "verb": ["W3"], None: ["S"]})
+        groups = store.annotations().group_by("pos", "pos", skip_missing=True)
+        self.assertNotIn(None, groups)
+        self.assertIsInstance(groups["adj"], tuple)
+
+    def test_build(self):
+        store = AnnotationStore.build(
+            id="test",
+            resources=[{"id": "testres", "text": "Hello world"}],
+            annotationsets=[{"id": "testdataset", "data": [{"id": "D1", "key": "type", "value": "word"}]}],
+            annotations=[
+                {"id": "A1", "target": {"resource": "testres", "begin": 0, "end": 5}, "data": ["D1"]},
+                {"id": "A2", "target": {"resource": "testres", "begin": 6, "end": 11}, "data": [{"set": "testdataset", "key": "type", "value": "word"}]},
+                {"id": "Sentence", "target": [{"annotation": "A1"}, {"annotation": "A2"}], "data": [{"set": "testdataset", "key": "type", "value": "sentence"}]},
+            ],
+        )
+        self.assertEqual(store.id, "test")
+        self.assertEqual(store.annotations_len(), 3)
+        self.assertEqual(str(store.annotation("A2")), "world")
+        self.assertTrue(store.annotation("Sentence").selector().is_multiselector())
+
+    def test_build_unresolved(self):
+        with self.assertRaisesRegex(StamError, "Annotation #1"):
+            AnnotationStore.build(
+                resources=[{"id": "testres", "text": "Hello world"}],
+                annotations=[
+                    {"id": "A1", "target": {"resource": "testres"}, "data": []},
+                    {"id": "A2", "target": {"annotation": "A3"}, "data": []},
+                ],
+            )
+
 if __name__ == "__main__":
     unittest.main()
 